@@ -0,0 +1,133 @@
+//! Throughput of `replace_nix_paths` on a synthetic multi-GB NAR, compared to the former regex based implementation.
+//!
+//! `cargo bench --bench replace_nix_paths` streams 4 GiB by default, set `BENCH_SIZE_MIB` to change it.
+
+use std::{collections::HashMap, io, pin::Pin, task::{Context, Poll}, time::Instant};
+use color_eyre::eyre;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Yields the same block over and over until `remaining` bytes have been read.
+struct SyntheticNar {
+  block: Vec<u8>,
+  pos: usize,
+  remaining: u64,
+}
+
+impl AsyncRead for SyntheticNar {
+  fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let l = (self.block.len() - self.pos).min(buf.remaining()).min(self.remaining as usize);
+    buf.put_slice(&self.block[self.pos..self.pos + l]);
+    self.pos = (self.pos + l) % self.block.len();
+    self.remaining -= l as u64;
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// Alternates binary-looking and path-heavy text-looking data, with a store path every ~4KiB,
+/// a bit like an ELF with its RPATH and strings.
+fn block(hashes: &[[u8; 32]]) -> Vec<u8> {
+  const ALPHABET: &[u8] = b"/nix/store/abcdefghijklmnopqrstuvwxyz0123456789\0\n";
+  let mut seed: u64 = 0x9e3779b97f4a7c15;
+  let mut block = Vec::with_capacity(BLOCK_SIZE);
+  while block.len() < BLOCK_SIZE - 4096 {
+    for _ in 0..4000 {
+      seed ^= seed << 13; seed ^= seed >> 7; seed ^= seed << 17;
+      if (block.len() / 65536) % 2 == 0 {
+        block.push(seed as u8);
+      } else {
+        block.push(ALPHABET[seed as usize % ALPHABET.len()]);
+      }
+    }
+    block.extend_from_slice(b"/nix/store/");
+    block.extend_from_slice(&hashes[block.len() % hashes.len()]);
+    block.extend_from_slice(b"-glibc-2.38-44/lib");
+  }
+  block.resize(BLOCK_SIZE, 0);
+  block
+}
+
+/// The implementation `replace_nix_paths` had before being ported to `StorePathAutomaton`.
+async fn replace_nix_paths_regex(mut reader: impl AsyncRead + Unpin, mut writer: impl AsyncWrite + Unpin, replacements: HashMap<[u8;32], [u8;32]>) -> eyre::Result<bool> {
+  const REPL_PATH_LEN : usize = 44;
+  const CHUNK : usize = 50;
+
+  let mut buf = [0; CHUNK + REPL_PATH_LEN ];
+  let mut buf_ahead = [0; CHUNK + REPL_PATH_LEN ];
+  let mut buf_l;
+  let mut buf_ahead_l;
+  let regex = regex::bytes::Regex::new(r"/nix/store/[0-9abcdfghijklmnpqrsvwxyz]{32}\-")?;
+
+  buf_l = 0;
+  loop {
+    let l = reader.read(&mut buf[buf_l..CHUNK]).await?;
+    buf_l += l;
+    if l == 0 || buf_l == CHUNK {
+      break;
+    }
+  }
+  buf_ahead[CHUNK..].copy_from_slice(&buf[..REPL_PATH_LEN]);
+
+  loop {
+    buf_ahead_l = 0;
+    loop {
+      let l = reader.read(&mut buf_ahead[buf_ahead_l..CHUNK]).await?;
+      buf_ahead_l += l;
+      if l == 0 || buf_ahead_l == CHUNK {
+        break;
+      }
+    }
+    buf[CHUNK..].copy_from_slice(&buf_ahead[..REPL_PATH_LEN]);
+    buf[..REPL_PATH_LEN].copy_from_slice(&buf_ahead[CHUNK..]);
+    let proc_l = buf_l + (buf_ahead_l).min(REPL_PATH_LEN);
+
+    let hash_offsets = regex.find_iter(&buf[..proc_l]).map(|m| m.start() + 11).collect::<Vec<_>>();
+    for hash_offset in hash_offsets {
+      let b: &[u8; 32] = &buf[hash_offset .. hash_offset + 32].try_into().unwrap();
+      if let Some(r) = replacements.get(b) {
+        buf[hash_offset .. hash_offset + 32].copy_from_slice(r);
+      } else {
+        return Ok(false);
+      }
+    }
+
+    if buf_ahead_l == CHUNK {
+      writer.write_all(&buf[..CHUNK]).await?;
+    } else {
+      writer.write_all(&buf[..proc_l]).await?;
+      break
+    };
+
+    std::mem::swap(&mut buf, &mut buf_ahead);
+    std::mem::swap(&mut buf_l, &mut buf_ahead_l);
+  }
+
+  Ok(true)
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+  let size = std::env::var("BENCH_SIZE_MIB").map_or(Ok(4096), |s| s.parse::<u64>())? * 1024 * 1024;
+
+  let hashes = [*b"abcdfghijklmnpqrsvwxyz0000000000", *b"0123456789abcdfghijklmnpqrsvwxyz"];
+  // map every hash to itself so that the regex implementation doesn't trip over hashes it already replaced
+  let replacements = hashes.iter().map(|h| (*h, *h)).collect::<HashMap<_, _>>();
+  let block = block(&hashes);
+
+  let start = Instant::now();
+  let ok = replace_nix_paths_regex(SyntheticNar { block: block.clone(), pos: 0, remaining: size }, tokio::io::sink(), replacements.clone()).await?;
+  assert!(ok);
+  let regex_elapsed = start.elapsed();
+  println!("regex:     {:>8.1} MiB/s ({size} bytes in {regex_elapsed:?})", size as f64 / 1024. / 1024. / regex_elapsed.as_secs_f64());
+
+  let start = Instant::now();
+  let ok = nar_alike_deduper::replace_nix_paths(&Default::default(), SyntheticNar { block, pos: 0, remaining: size }, tokio::io::sink(), replacements).await?;
+  assert!(ok);
+  let automaton_elapsed = start.elapsed();
+  println!("automaton: {:>8.1} MiB/s ({size} bytes in {automaton_elapsed:?})", size as f64 / 1024. / 1024. / automaton_elapsed.as_secs_f64());
+
+  println!("speedup:   {:>8.1}x", regex_elapsed.as_secs_f64() / automaton_elapsed.as_secs_f64());
+
+  Ok(())
+}
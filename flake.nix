@@ -55,6 +55,43 @@
           options = {
             nar-alike-deduper = {
               enable = mkEnableOption "nar-alike-deduper";
+              trustedPublicKeys = mkOption {
+                type = types.listOf types.str;
+                default = [ "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=" ];
+                description = "Keys upstream narinfos must be signed with";
+              };
+              upstreams = mkOption {
+                type = types.listOf types.str;
+                default = [ "https://cache.nixos.org" ];
+                example = [ "https://cache.nixos.org" "https://nix-community.cachix.org?priority=41&trusted-public-keys=nix-community.cachix.org-1:mB9FSh9qf2dCimDSUo8Zy7bkq5CX+/rkCWyvRCYg3Fs=" ];
+                description = "Binary caches to fetch from (http(s):// or file://), as `<url>?priority=<n>&trusted-public-keys=<key>,<key>&timeout=<seconds>&trusted=1`, lower priorities first. The narinfos of `trusted=1` ones aren't required to be signed, e.g. unsigned `file://` caches";
+              };
+              compression = mkOption {
+                type = types.enum [ "none" "xz" "zstd" "bzip2" "br" "gzip" ];
+                default = "none";
+                description = "Compression of the served NARs, transcoded from the upstream one if they differ";
+              };
+              narCacheSize = mkOption {
+                type = types.ints.unsigned;
+                default = 10 * 1024 * 1024 * 1024;
+                description = "Size, in bytes, above which the least recently used NARs are evicted from the on-disk cache";
+              };
+              negativeTtl = mkOption {
+                type = types.ints.unsigned;
+                default = 3600;
+                description = "Seconds during which narinfos no upstream has are answered as missing without asking again, 0 to always ask. Cleared with `DELETE /admin/misses`";
+              };
+              secretKeyFile = mkOption {
+                type = types.nullOr types.str;
+                default = null;
+                description = "Path to the secret key (as created by `nix key generate-secret`) the served narinfos are signed with";
+              };
+              dedupDbUrl = mkOption {
+                type = types.nullOr types.str;
+                default = null;
+                example = "postgresql://postgres@10.42.0.7/nar-dedup";
+                description = "Postgres database download-and-compute-dedup-hashes stores dedup hashes in, to also rebuild NARs from installed paths with the same dedup hash but another name";
+              };
               #port = mkOption {
               #  type = types.int;
               #  default = 8080;
@@ -87,6 +124,20 @@
               wantedBy = ["multi-user.target"];
               after = [ "network.target" "network-online.target"];
       
+              environment = {
+                STORE_DIR = builtins.storeDir;
+                TRUSTED_PUBLIC_KEYS = concatStringsSep " " cfg.trustedPublicKeys;
+                UPSTREAMS = concatStringsSep " " cfg.upstreams;
+                COMPRESSION = cfg.compression;
+                NAR_CACHE_DIR = "/var/lib/nar-alike-deduper/nar-cache";
+                NAR_CACHE_SIZE = toString cfg.narCacheSize;
+                NEGATIVE_TTL = toString cfg.negativeTtl;
+              } // optionalAttrs (cfg.secretKeyFile != null) {
+                SECRET_KEY_FILE = cfg.secretKeyFile;
+              } // optionalAttrs (cfg.dedupDbUrl != null) {
+                DEDUP_DB_URL = cfg.dedupDbUrl;
+              };
+      
               serviceConfig = {
                 ExecStart = "${inputs.self.packages.${pkgs.stdenv.hostPlatform.system}.default}/bin/substituer"; # --port ${toString cfg.port}";
                 Restart = "always";
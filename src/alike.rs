@@ -0,0 +1,207 @@
+//! Rebuilds NARs from installed store paths which only differ from them by store path hashes.
+//!
+//! Two builds of the same package with different dependencies usually only differ by the hashes of the store paths they reference.
+//! Such an "alike" path can be dumped locally and rewritten into the requested NAR instead of downloading it.
+
+use std::{collections::HashMap, io, time::SystemTime};
+use color_eyre::eyre;
+use tokio::io::AsyncRead;
+use crate::{AsyncSha256Hasher, BUF_SIZE, hash::NixHash, nar::dump_path, narinfo::NarInfo, store_path::{StoreDir, StorePath}, store_path_automaton::{StorePathReader, UnknownHashError}};
+
+const MAX_CANDIDATES: usize = 4; // each candidate costs a full dump of the installed path
+
+/// The store paths installed in a store dir, by hash and by name.
+#[derive(Debug, Default)]
+pub struct StoreIndex {
+  mtime: Option<SystemTime>, // of the store dir when it was last scanned
+  names: HashMap<[u8; 32], String>,
+  hashes: HashMap<String, Vec<[u8; 32]>>,
+}
+
+impl StoreIndex {
+  /// Rescans the store dir if it changed since the last scan.
+  pub async fn refresh(&mut self, store_dir: &StoreDir) -> io::Result<()> {
+    let mtime = tokio::fs::metadata(store_dir.as_str()).await?.modified()?;
+    if self.mtime == Some(mtime) {
+      return Ok(());
+    }
+
+    self.names.clear();
+    self.hashes.clear();
+    let mut dir = tokio::fs::read_dir(store_dir.as_str()).await?;
+    while let Some(entry) = dir.next_entry().await? {
+      let Some(path) = entry.file_name().to_str().and_then(|n| StorePath::from_base_name(n).ok()) else {
+        continue; // e.g. ".links"
+      };
+      self.names.insert(*path.hash(), path.name().to_owned());
+      self.hashes.entry(path.name().to_owned()).or_default().push(*path.hash());
+    }
+    self.mtime = Some(mtime);
+
+    Ok(())
+  }
+
+  pub fn name(&self, hash: &[u8; 32]) -> Option<&str> {
+    self.names.get(hash).map(String::as_str)
+  }
+
+  /// The hashes of the installed store paths named `name`.
+  pub fn hashes(&self, name: &str) -> &[[u8; 32]] {
+    self.hashes.get(name).map_or(&[], Vec::as_slice)
+  }
+}
+
+/// An installed store path and the hash replacements turning its NAR into the target's.
+#[derive(Debug, Clone)]
+pub struct Alike {
+  pub store_path: StorePath,
+  pub replacements: HashMap<[u8; 32], [u8; 32]>,
+}
+
+impl Alike {
+  /// Maps the installed `store_path` to `target`, and the installed paths with the name of a reference of `target` to that reference.
+  pub fn new(index: &StoreIndex, store_path: StorePath, target: &NarInfo) -> Self {
+    let mut replacements = HashMap::new();
+    for reference in &target.references {
+      if reference == &target.store_path {
+        continue; // self-reference, mapped from the installed path itself
+      }
+      for local_hash in index.hashes(reference.name()) {
+        replacements.insert(*local_hash, *reference.hash());
+      }
+    }
+    replacements.insert(*store_path.hash(), *target.store_path.hash());
+    Self { store_path, replacements }
+  }
+
+  /// Dumps the installed path and rewrites it into the target NAR.
+  pub fn reconstruct(&self, store_dir: &StoreDir) -> impl AsyncRead + Send + Unpin + 'static {
+    let path = self.store_path.to_absolute_path(store_dir);
+    StorePathReader::with_store_dir(store_dir, BUF_SIZE, dump_path(path), self.replacements.clone())
+  }
+
+  /// Checks that the reconstructed NAR is exactly the target's.
+  pub async fn verify(&self, store_dir: &StoreDir, target: &NarInfo) -> eyre::Result<bool> {
+    let mut hasher = AsyncSha256Hasher::new();
+    let size = match tokio::io::copy(&mut self.reconstruct(store_dir), &mut hasher).await {
+      Ok(size) => size,
+      Err(e) if UnknownHashError::from_io(&e).is_some() => return Ok(false),
+      Err(e) => return Err(e.into()),
+    };
+    Ok(size == target.nar_size && NixHash::sha256(hasher.finalize()) == target.nar_hash)
+  }
+}
+
+/// Picks installed store paths which may be rewritten into `target`, the likeliest first, without reading them.
+///
+/// `dedup_hashes` are the known dedup hashes of store paths, by hash, see `DedupDb::dedup_hashes`. The installed paths sharing the
+/// target's come first, whatever their name, then the other ones with the target's name, unless their dedup hash is known to differ.
+/// Paths which `failed` to be rewritten into the target before are left out. Rebuilt NARs must still be checked against the target's
+/// `NarHash`, with `verify` or while they're read with a `VerifyingReader`.
+pub fn find_alikes(index: &StoreIndex, target: &NarInfo, dedup_hashes: &HashMap<[u8; 32], String>, failed: &[StorePath]) -> eyre::Result<Vec<Alike>> {
+  let target_path = &target.store_path;
+  let target_dedup = dedup_hashes.get(target_path.hash());
+
+  let mut same_dedup = dedup_hashes.iter()
+    .filter(|(hash, dedup)| Some(*dedup) == target_dedup && index.name(hash).is_some())
+    .map(|(hash, _)| *hash)
+    .collect::<Vec<_>>();
+  same_dedup.sort();
+  let same_name = index.hashes(target_path.name()).iter().copied().filter(|hash| {
+    match (dedup_hashes.get(hash), target_dedup) {
+      (Some(dedup), Some(target_dedup)) => dedup == target_dedup,
+      _ => true,
+    }
+  });
+
+  let mut alikes: Vec<Alike> = Vec::new();
+  for hash in same_dedup.into_iter().chain(same_name) {
+    if alikes.len() == MAX_CANDIDATES {
+      break;
+    }
+    if &hash == target_path.hash() || alikes.iter().any(|a| a.store_path.hash() == &hash) || failed.iter().any(|p| p.hash() == &hash) {
+      continue;
+    }
+    let name = index.name(&hash).expect("candidates are installed");
+    alikes.push(Alike::new(index, StorePath::new(hash, name)?, target));
+  }
+  Ok(alikes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::AsyncReadExt;
+
+  #[tokio::test]
+  async fn rebuild() -> eyre::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let store_dir = StoreDir::new(dir.path().to_str().unwrap())?;
+    let path = |base_name: &str| dir.path().join(base_name);
+
+    // an installed build of hello, linked to an old glibc
+    std::fs::create_dir(path("00000000000000000000000000000000-glibc-2.38"))?;
+    std::fs::create_dir_all(path("11111111111111111111111111111111-hello-2.12/bin"))?;
+    std::fs::write(
+      path("11111111111111111111111111111111-hello-2.12/bin/hello"),
+      format!("{store_dir}/00000000000000000000000000000000-glibc-2.38/lib/libc.so {store_dir}/11111111111111111111111111111111-hello-2.12/share"),
+    )?;
+
+    // the same build linked to a new glibc
+    let expected = tempfile::tempdir()?;
+    std::fs::create_dir(expected.path().join("bin"))?;
+    std::fs::write(
+      expected.path().join("bin/hello"),
+      format!("{store_dir}/22222222222222222222222222222222-glibc-2.38/lib/libc.so {store_dir}/33333333333333333333333333333333-hello-2.12/share"),
+    )?;
+    let mut nar = Vec::new();
+    dump_path(expected.path()).read_to_end(&mut nar).await?;
+    let mut hasher = AsyncSha256Hasher::new();
+    tokio::io::copy(&mut nar.as_slice(), &mut hasher).await?;
+
+    let mut target: NarInfo = format!("StorePath: {store_dir}/33333333333333333333333333333333-hello-2.12
+URL: nar/hello.nar
+NarHash: {}
+NarSize: {}
+References: 22222222222222222222222222222222-glibc-2.38 33333333333333333333333333333333-hello-2.12
+", NixHash::sha256(hasher.finalize()), nar.len()).parse()?;
+
+    let mut index = StoreIndex::default();
+    index.refresh(&store_dir).await?;
+    assert_eq!(index.name(b"00000000000000000000000000000000"), Some("glibc-2.38"));
+
+    let alikes = find_alikes(&index, &target, &HashMap::new(), &[])?;
+    assert_eq!(alikes.len(), 1);
+    let alike = &alikes[0];
+    assert_eq!(alike.store_path.to_string(), "11111111111111111111111111111111-hello-2.12");
+    let mut rebuilt = Vec::new();
+    alike.reconstruct(&store_dir).read_to_end(&mut rebuilt).await?;
+    assert_eq!(rebuilt, nar);
+    assert!(alike.verify(&store_dir, &target).await?);
+
+    target.nar_size += 1;
+    assert!(!alike.verify(&store_dir, &target).await?);
+    target.nar_size -= 1;
+    assert!(find_alikes(&index, &target, &HashMap::new(), std::slice::from_ref(&alike.store_path))?.is_empty());
+
+    // renamed, found through its dedup hash
+    target.store_path = "33333333333333333333333333333333-hello-2.13".parse()?;
+    assert!(find_alikes(&index, &target, &HashMap::new(), &[])?.is_empty());
+    let dedup_hashes = HashMap::from([
+      (*b"33333333333333333333333333333333", "a".repeat(52)),
+      (*b"11111111111111111111111111111111", "a".repeat(52)),
+      (*b"44444444444444444444444444444444", "a".repeat(52)), // not installed
+    ]);
+    let alikes = find_alikes(&index, &target, &dedup_hashes, &[])?;
+    assert_eq!(alikes.iter().map(|a| a.store_path.to_string()).collect::<Vec<_>>(), ["11111111111111111111111111111111-hello-2.12"]);
+
+    // same name, but known to differ
+    target.store_path = "33333333333333333333333333333333-hello-2.12".parse()?;
+    let dedup_hashes = HashMap::from([
+      (*b"33333333333333333333333333333333", "a".repeat(52)),
+      (*b"11111111111111111111111111111111", "b".repeat(52)),
+    ]);
+    assert!(find_alikes(&index, &target, &dedup_hashes, &[])?.is_empty());
+    Ok(())
+  }
+}
@@ -31,7 +31,7 @@ struct Params {
   path: String
 }
 
-async fn get_path(State(state): State<()>, Path(params): Path<Params>) -> impl IntoResponse {
+async fn get_path(State(_state): State<()>, Path(params): Path<Params>) -> impl IntoResponse {
   tracing::info!(params.path, "GET");
   StatusCode::OK
 }
@@ -1,22 +1,24 @@
-use async_channel::Receiver;
 use color_eyre::eyre;
-use nar_alike_deduper::AsyncSha256Hasher;
-use sqlx::{postgres::{PgPoolOptions, PgTypeInfo, types::Oid}, Row, Column};
-use futures::{TryStreamExt, StreamExt};
-use tokio_util::io::StreamReader;
-use std::{fmt::Write, collections::HashMap, sync::{atomic::{AtomicU64, Ordering}, Arc}};
-          
-async fn process_hash2(client: &reqwest::Client, hash: String, total: &Arc<AtomicU64>, i: &Arc<AtomicU64>) -> eyre::Result<()> {
-  let r = client.get(&format!("http://cache.nixos.org/{}.narinfo", hash)).send().await?;
-  if r.status() != 200 {
-    return Err(eyre::eyre!("bad status: {}", r.status()));
+use nar_alike_deduper::{AsyncDedupHasher, AsyncSha256Hasher, compression::Compression, dedup_db::DedupDb, narinfo::NarInfo, signing::PublicKey, store_path::StoreDir, upstream::{Upstream, parse_upstreams}};
+use sqlx::{postgres::PgPoolOptions, Row};
+use futures::TryStreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::{sync::{atomic::{AtomicU64, Ordering}, Arc}};
+
+/// Fetches the narinfo from the first upstream which has it.
+async fn fetch_narinfo<'a>(upstreams: &'a [Upstream], hash: &str) -> eyre::Result<(NarInfo, String, &'a Upstream)> {
+  for upstream in upstreams {
+    if let Some((narinfo, text)) = upstream.narinfo(hash).await? {
+      return Ok((narinfo, text, upstream));
+    }
   }
-  let r = r.text().await?; // maybe https is faster
-  let d = r.lines().filter_map(|l| {
-    l.find(": ").map(|i| (&l[..i], &l[i+2..]))
-  }).collect::<HashMap<_,_>>();
-  //dbg!(&d);
-  let size: u64 = d.get("FileSize").ok_or(eyre::eyre!("no file size"))?.parse()?;
+  Err(eyre::eyre!("narinfo of {hash} not found upstream"))
+}
+
+#[allow(dead_code)] // only fetches the narinfo, to estimate the total download size
+async fn process_hash2(upstreams: &[Upstream], hash: String, total: &Arc<AtomicU64>, i: &Arc<AtomicU64>) -> eyre::Result<()> {
+  let (narinfo, _, _) = fetch_narinfo(upstreams, &hash).await?;
+  let size = narinfo.file_size.ok_or(eyre::eyre!("no file size"))?;
   total.fetch_add(size, Ordering::SeqCst);
   let total = total.load(Ordering::SeqCst);
   let i = i.load(Ordering::SeqCst);
@@ -24,46 +26,39 @@ async fn process_hash2(client: &reqwest::Client, hash: String, total: &Arc<Atomi
   Ok(())
 }
 
-async fn process_hash(client: &reqwest::Client, hash: String, total: &Arc<AtomicU64>, i: &Arc<AtomicU64>) -> eyre::Result<()> {
+async fn process_hash(upstreams: &[Upstream], store_dir: &StoreDir, dedup_db: &DedupDb, hash: String, total: &Arc<AtomicU64>, i: &Arc<AtomicU64>) -> eyre::Result<()> {
   println!("processing {}", hash);
-  let r = client.get(&format!("http://cache.nixos.org/{}.narinfo", hash)).send().await?;
-  if r.status() != 200 {
-    return Err(eyre::eyre!("bad status: {}", r.status()));
-  }
-  let r = r.text().await?; // maybe https is faster
-  println!("{}", r);
-  let d = r.lines().filter_map(|l| {
-    l.find(": ").map(|i| (&l[..i], &l[i+2..]))
-  }).collect::<HashMap<_,_>>();
-  //dbg!(&d);
-  let size: u64 = d.get("FileSize").ok_or(eyre::eyre!("no file size"))?.parse()?;
+  let (narinfo, text, upstream) = fetch_narinfo(upstreams, &hash).await?;
+  println!("{}", text);
+  let size = narinfo.file_size.ok_or(eyre::eyre!("no file size"))?;
   total.fetch_add(size, Ordering::SeqCst);
   let total = total.load(Ordering::SeqCst);
   let i = i.load(Ordering::SeqCst);
   println!("{}: {} / {} = {}", hash, total, i, total / i);
 
-  let url = d.get("URL").ok_or(eyre::eyre!("no url"))?;
-  let r = client.get(format!("http://cache.nixos.org/{}", url)).send().await?;
-  if r.status() != 200 {
-    return Err(eyre::eyre!("bad status: {}", r.status()));
-  }
-
-  // convert a Steamer of Bytes to an AsyncReader
-  let bs = r.bytes_stream();
-  let ms = bs.map(|result| result.map_err(|err| {
-    std::io::Error::new(std::io::ErrorKind::Other, err)
-  }));
-  let sr = StreamReader::new(ms);
-
+  // from the same upstream as the narinfo
+  let sr = upstream.file(&narinfo.url).await?.ok_or_else(|| eyre::eyre!("{} not found on {}", narinfo.url, upstream.url))?;
 
+  // feed the decompressed NAR to both hashers
+  let mut decoder = Compression::of(&narinfo)?.decoder(sr);
   let mut hasher = AsyncSha256Hasher::new();
-  tokio::io::copy(
-    &mut async_compression::tokio::bufread::XzDecoder::new(sr),
-    &mut hasher
-  ).await?;
+  let self_hash: [u8; 32] = hash.as_bytes().try_into()?;
+  let mut dedup_hasher = AsyncDedupHasher::with_store_dir(store_dir, Some(self_hash));
+  let mut buf = vec![0; 1024 * 1024];
+  loop {
+    let l = decoder.read(&mut buf).await?;
+    if l == 0 {
+      break;
+    }
+    hasher.write_all(&buf[..l]).await?;
+    dedup_hasher.write_all(&buf[..l]).await?;
+  }
   let narhash = hasher.finalize();
   println!("computed hash: {}", hex::encode(narhash));
   println!("computed hash in base32: {}", nix_base32::to_nix_base32(&narhash));
+  let dedup_hash = dedup_hasher.finalize();
+  println!("computed dedup hash in base32: {}", nix_base32::to_nix_base32(&dedup_hash));
+  dedup_db.insert(&hash, &dedup_hash).await?;
 
   Ok(())
 }
@@ -72,12 +67,18 @@ async fn process_hash(client: &reqwest::Client, hash: String, total: &Arc<Atomic
 async fn main() -> eyre::Result<()> {
   nar_alike_deduper::setup_logging()?;
   let db_addr = std::env::var("DB_ADDR").unwrap_or("10.42.0.7".to_string());
+  let store_dir = std::env::var("STORE_DIR").map_or(Ok(StoreDir::default()), |s| s.parse())?;
+  let trusted_keys = std::env::var("TRUSTED_PUBLIC_KEYS")
+    .unwrap_or("cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".to_string())
+    .split_whitespace().map(str::parse).collect::<eyre::Result<Vec<PublicKey>>>()?;
+  let upstreams = Arc::new(parse_upstreams(&std::env::var("UPSTREAMS").unwrap_or("https://cache.nixos.org".to_string()), &trusted_keys)?);
 
   let pool = PgPoolOptions::new()
     .max_connections(5)
     .connect(&format!("postgresql://postgres@{db_addr}/nar-dedup")).await?;
 
   tracing::info!("connected to db");
+  let dedup_db = DedupDb::new(pool.clone()).await?;
   
   // setting up workers
   let total = Arc::new(AtomicU64::new(0));
@@ -88,17 +89,17 @@ async fn main() -> eyre::Result<()> {
     tracing::info!(thread_id, "starting insertion");
 
     let recv = recv.clone();
-    let pool = pool.clone();
     let total = total.clone();
     let i = i.clone();
+    let store_dir = store_dir.clone();
+    let upstreams = upstreams.clone();
+    let dedup_db = dedup_db.clone();
 
     tokio::task::spawn(async move {
-      let client = reqwest::Client::new();
-
       while let Ok(hash) = recv.recv().await {
         i.fetch_add(1, Ordering::SeqCst);
 
-        let r = process_hash(&client, hash, &total, &i).await;
+        let r = process_hash(&upstreams, &store_dir, &dedup_db, hash, &total, &i).await;
 
         if let Err(e) = r {
           tracing::error!(thread_id, ?e);
@@ -110,18 +111,9 @@ async fn main() -> eyre::Result<()> {
   let mut res = sqlx::query(r#"select * from store_hashes"#)
     .fetch(&pool);
 
+  #[allow(clippy::never_loop)] // only process the first row for now
   while let Some(row) = res.try_next().await? {
     let r: eyre::Result<()> = async {
-      
-      let s = row.columns().into_iter().filter_map(|c|{
-        if let Some(Oid(1043)) = c.type_info().oid() {
-          Some(format!("{}: {}", c.name(), row.try_get::<&str, _>(c.ordinal()).unwrap_or("<NULL>")))
-        } else {
-          None
-        }
-      }).collect::<Vec<_>>().join(", ");
-      //println!("{}", s);
-
       let hash = row.try_get::<&str, _>("store_hash")?;
       send.send(hash.to_owned()).await?;
 
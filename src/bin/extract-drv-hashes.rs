@@ -2,6 +2,7 @@ use async_channel::Receiver;
 use chrono::{DateTime, Utc};
 use color_eyre::eyre::{self, OptionExt};
 use duct::cmd;
+use nar_alike_deduper::store_path::{StoreDir, StorePath};
 use regex::Regex;
 use reqwest::header::USER_AGENT;
 use sqlx::postgres::PgPoolOptions;
@@ -38,7 +39,7 @@ async fn get_commit_date(revision: &str) -> eyre::Result<DateTime<Utc>> {
 }
 
 #[tracing::instrument(fields(revision))]
-async fn update(branch: &str, system: &str, db_addr: &str) -> eyre::Result<()> {
+async fn update(branch: &str, system: &str, db_addr: &str, store_dir: &StoreDir) -> eyre::Result<()> {
   tracing::info!("start");
 
   let pool = PgPoolOptions::new()
@@ -71,7 +72,7 @@ async fn update(branch: &str, system: &str, db_addr: &str) -> eyre::Result<()> {
 
   let revision = get_latest_revision(branch).await?;
 
-  tracing::Span::current().record("revision", &revision.as_str());
+  tracing::Span::current().record("revision", revision.as_str());
 
   let res = sqlx::query("select * from completed_drv_sets where branch = $1 and revision = $2 and system = $3")
     .bind(branch)
@@ -79,7 +80,7 @@ async fn update(branch: &str, system: &str, db_addr: &str) -> eyre::Result<()> {
     .bind(system)
     .fetch_all(&pool).await?;
 
-  if res.len() > 0 {
+  if !res.is_empty() {
     tracing::info!("already in db");
     return Ok(());
   }
@@ -106,6 +107,7 @@ async fn update(branch: &str, system: &str, db_addr: &str) -> eyre::Result<()> {
     let system = system.to_owned();
     let revision = revision.to_owned();
     let pool = pool.clone();
+    let store_dir = store_dir.clone();
     let regex = Regex::new(r"^(.*?)-([^a-zA-Z].*)$").unwrap(); // mimics https://github.com/NixOS/nix/blob/0fb5024d8df46a47f5367c5b0a51f0b2f6d50032/src/libstore/names.cc#L30
 
     tokio::task::spawn(async move {
@@ -123,8 +125,9 @@ async fn update(branch: &str, system: &str, db_addr: &str) -> eyre::Result<()> {
             .iter().map(|v| v.as_str().unwrap_or_default()).collect::<Vec<_>>();
 
           for sp in store_paths {
-            let sh = sp[11..43].to_string();
-            let name = sp[44..].to_string();
+            let sp = StorePath::from_absolute_path(&store_dir, sp)?;
+            let sh = sp.hash_str().to_owned();
+            let name = sp.name().to_owned();
 
             let (pname, version) = match regex.captures(&name) {
               Some(c) => (c.get(1).map(|e| e.as_str()), c.get(2).map(|e| e.as_str())),
@@ -180,6 +183,7 @@ async fn update(branch: &str, system: &str, db_addr: &str) -> eyre::Result<()> {
 
 }
 
+#[allow(dead_code)] // only used manually to reset the database
 async fn delete() -> eyre::Result<()> {
   let pool = PgPoolOptions::new()
     .max_connections(5)
@@ -196,9 +200,10 @@ async fn delete() -> eyre::Result<()> {
 async fn main() -> eyre::Result<()> {
   nar_alike_deduper::setup_logging()?;
   let db_addr = std::env::var("DB_ADDR").unwrap_or("10.42.0.7".to_string());
+  let store_dir = std::env::var("STORE_DIR").map_or(Ok(StoreDir::default()), |s| s.parse())?;
 
   loop {
-    if let Err(e) = update("nixos-23.11", "x86_64-linux", &db_addr).await {
+    if let Err(e) = update("nixos-23.11", "x86_64-linux", &db_addr, &store_dir).await {
       tracing::error!(?e);
     }
     tracing::info!("sleeping 5 min");
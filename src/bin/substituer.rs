@@ -1,14 +1,13 @@
-use std::{collections::HashMap, error::Error, io, rc::Rc, sync::Arc, time::Duration};
+use std::{collections::HashMap, error::Error, io::{self, SeekFrom}, sync::Arc, time::Duration};
 
-use axum::{Router, routing::get, response::{IntoResponse, Response}, extract::{State, Path, MatchedPath}, http::{StatusCode, Request}, body::Body};
-use chrono::format;
+use axum::{Router, routing::{delete, get}, response::{IntoResponse, Response}, extract::{State, Path}, http::{HeaderMap, HeaderValue, StatusCode, Request, header}, body::Body};
 use color_eyre::eyre::{self, anyhow};
-use nar_alike_deduper::AsyncSha256Hasher;
+use nar_alike_deduper::{VerifyingReader, alike::{self, Alike, StoreIndex}, compression::{self, Compression}, dedup_db::DedupDb, hash::NixHash, nar, nar_cache::NarCache, narinfo::NarInfo, narinfo_db::NarInfoDb, range::ByteRange, signing::{PublicKey, SecretKey}, store_path::{StoreDir, StorePath}, upstream::{Upstream, parse_upstreams}};
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
-use tokio_stream::StreamExt;
-use tokio_util::io::{ReaderStream, StreamReader};
-use tower_http::{trace::TraceLayer, classify::ServerErrorsFailureClass};
+use sqlx::postgres::PgPoolOptions;
+use tokio::{io::{AsyncReadExt, AsyncSeekExt}, sync::Mutex};
+use tokio_util::io::ReaderStream;
+use tower_http::trace::TraceLayer;
 use tracing::{info_span, Span};
 
 /// A trait to represent a response that can be returned from an HTTP handler where we can easily use `?` to return an error.
@@ -18,11 +17,11 @@ impl<T: IntoResponse> IntoResultReponse for Result<T> {}
 type Result<T, E = HttpError> = std::result::Result<T, E>;
 
 /// A extention trait to Result to easily convert an error into a `HttpError` with a status code.
-trait ResultExt<T: IntoResponse> {
+trait ResultExt<T> {
   fn err_with_status(self, status: StatusCode) -> Result<T>;
 } 
 
-impl<T: IntoResponse, E: Into<Box<dyn Error>>> ResultExt<T> for std::result::Result<T, E> {
+impl<T, E: Into<Box<dyn Error>>> ResultExt<T> for std::result::Result<T, E> {
   fn err_with_status(self, status:StatusCode) -> Result<T> {
     self.map_err(|error| {
       HttpError::new(status, error)
@@ -63,8 +62,44 @@ impl IntoResponse for HttpError {
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
   nar_alike_deduper::setup_logging()?;
-
-  http_server(MyState::default()).await?;
+  let store_dir = std::env::var("STORE_DIR").map_or(Ok(StoreDir::default()), |s| s.parse())?;
+
+  let db = NarInfoDb::open(std::env::var("DB_PATH").unwrap_or("substituer.redb".to_string()))?;
+  // narinfos we serve are signed with this key, if any
+  let secret_key = match std::env::var("SECRET_KEY_FILE") {
+    Ok(path) => Some(Arc::new(tokio::fs::read_to_string(path).await?.parse::<SecretKey>()?)),
+    Err(_) => None,
+  };
+  let trusted_keys = std::env::var("TRUSTED_PUBLIC_KEYS")
+    .unwrap_or("cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".to_string())
+    .split_whitespace().map(str::parse).collect::<eyre::Result<Vec<PublicKey>>>()?;
+  let upstreams = parse_upstreams(&std::env::var("UPSTREAMS").unwrap_or("https://cache.nixos.org".to_string()), &trusted_keys)?;
+  // of the NARs we serve
+  let compression = std::env::var("COMPRESSION").map_or(Ok(Compression::None), |s| s.parse())?;
+  // how long narinfos no upstream has are remembered as missing
+  let negative_ttl = Duration::from_secs(std::env::var("NEGATIVE_TTL").map_or(Ok(3600), |s| s.parse())?);
+  let nar_cache = NarCache::open(
+    std::env::var("NAR_CACHE_DIR").unwrap_or("nar-cache".to_string()),
+    std::env::var("NAR_CACHE_SIZE").map_or(Ok(10 << 30), |s| s.parse())?, // in bytes
+  )?;
+  // dedup hashes filled by download-and-compute-dedup-hashes, e.g. "postgresql://postgres@10.42.0.7/nar-dedup", to find alike paths by them
+  let dedup_db = match std::env::var("DEDUP_DB_URL") {
+    Ok(url) => Some(DedupDb::new(PgPoolOptions::new().max_connections(5).connect(&url).await?).await?),
+    Err(_) => None,
+  };
+
+  let state = MyState {
+    store_dir: Arc::new(store_dir),
+    db,
+    secret_key,
+    upstreams: Arc::new(upstreams),
+    compression,
+    nar_cache,
+    negative_ttl,
+    store_index: Default::default(),
+    dedup_db,
+  };
+  http_server(state).await?;
 
   Ok(())
 }
@@ -75,90 +110,419 @@ struct Params {
   path: String
 }
 
-async fn get_nar(State(state): State<MyState>, Path(params): Path<Params>) -> impl IntoResultReponse {
-  if params.path.ends_with(".nar.xz") {
-    let r = reqwest::Client::new().get(format!("https://cache.nixos.org/nar/{}", params.path)).send().await?;
-    let status = StatusCode::from_u16(u16::from(r.status())).unwrap();
-    let body = Body::from_stream(r.bytes_stream());
-    Ok((status, IntoResponse::into_response(body)))
-  } else if params.path.ends_with(".nar") {
-    let xz_hash = state.data.read().await.get(&params.path).ok_or(anyhow!("No hash found for nar"))?.to_owned();
-    let r = reqwest::Client::new().get(format!("https://cache.nixos.org/nar/{}.nar.xz", xz_hash)).send().await?;
-    let status = StatusCode::from_u16(u16::from(r.status())).unwrap();
+/// What a NAR url refers to.
+struct NarRequest {
+  url: String, // without the compression extension
+  compression: Compression, // of what's served
+  narinfo: NarInfo,
+  upstream: Upstream, // the narinfo comes from
+  passthrough: Option<(NixHash, u64)>,
+  alikes: Vec<Alike>, // to try to rebuild the NAR from, in order
+}
 
+impl NarRequest {
+  async fn new(state: &MyState, path: &str) -> Result<Self> {
+    // NARs are looked up without the compression extension
+    let Some((url, compression)) = Compression::ALL.into_iter().find_map(|c| {
+      path.strip_suffix(c.extension()).filter(|u| u.ends_with(".nar")).map(|u| (u.to_owned(), c))
+    }) else {
+      return Err(anyhow!("unsupported NAR url")).err_with_status(StatusCode::NOT_FOUND);
+    };
+    let hash = state.db.nar_url(&url).await?.ok_or(anyhow!("No narinfo found for nar")).err_with_status(StatusCode::NOT_FOUND)?;
+    let (narinfo, upstream) = fetch_narinfo(state, &hash).await?.ok_or(anyhow!("No narinfo found upstream for nar")).err_with_status(StatusCode::NOT_FOUND)?;
+    let passthrough = passthrough(&narinfo, compression);
+    let alikes = find_alikes(state, &url, &narinfo).await;
+    Ok(Self { url, compression, narinfo, upstream, passthrough, alikes })
+  }
 
+  /// The hash, compression and size of what's cached: the upstream file when it's passed through, the NAR otherwise.
+  fn cached(&self) -> (NixHash, Compression, u64) {
+    match &self.passthrough {
+      Some((file_hash, file_size)) => (file_hash.clone(), self.compression, *file_size),
+      None => (self.narinfo.nar_hash.clone(), Compression::None, self.narinfo.nar_size),
+    }
+  }
 
-    // convert a Steamer of Bytes to an AsyncReader
-    let bs = r.bytes_stream();
-    let ms = bs.map(|result| result.map_err(|err| {
-      std::io::Error::new(std::io::ErrorKind::Other, err)
-    }));
-    let sr = StreamReader::new(ms);
+  /// The size of what's served, which is only known when it isn't compressed on the fly.
+  async fn size(&self, state: &MyState) -> Option<u64> {
+    let (hash, compression, size) = self.cached();
+    let rebuilt = !state.nar_cache.contains(&hash, compression) && !self.alikes.is_empty();
+    if rebuilt {
+      return (self.compression == Compression::None).then_some(self.narinfo.nar_size);
+    }
+    (compression == self.compression).then_some(size)
+  }
+}
 
+async fn get_nar(State(state): State<MyState>, Path(params): Path<Params>, headers: HeaderMap) -> impl IntoResultReponse {
+  let request = NarRequest::new(&state, &params.path).await?;
+  let size = request.size(&state).await;
+  let range = size.map_or(ByteRange::Full, |size| ByteRange::parse(headers.get(header::RANGE).and_then(|h| h.to_str().ok()), size));
+  if range == ByteRange::Unsatisfiable {
+    let size = size.expect("ranges are only parsed when the size is known");
+    return Ok((StatusCode::RANGE_NOT_SATISFIABLE, [(header::CONTENT_RANGE, format!("bytes */{size}"))].into_response()));
+  }
 
-    let ds = async_compression::tokio::bufread::XzDecoder::new(sr);
-    let s = ReaderStream::new(ds);
+  let (reader, position) = open_nar(&state, &request, range.start()).await?;
+  let reader = read_range(reader, &range, position).await?;
+  let store_path = request.narinfo.store_path.clone();
+  let s = futures::TryStreamExt::inspect_err(ReaderStream::new(reader), move |e| {
+    tracing::error!(error = %e, %store_path, "failed to serve NAR");
+  });
 
+  Ok(nar_response(Body::from_stream(s), size, &range))
+}
 
-    let body = Body::from_stream(s);
-    Ok((status, IntoResponse::into_response(body)))
-  } else {
-    Err(anyhow!("Only .nar.xz files are supported").into())
+/// Opens what's served for `request` from the cache, an alike path or its upstream, and returns where it is: at `start` if it could seek there, at 0 otherwise.
+async fn open_nar(state: &MyState, request: &NarRequest, start: u64) -> Result<(compression::Reader, u64)> {
+  let NarRequest { compression, narinfo, upstream, passthrough, alikes, .. } = request;
+  let (cached_hash, cached_compression, _) = request.cached();
+  if let Some((mut file, _)) = state.nar_cache.get(&cached_hash, cached_compression).await? {
+    tracing::info!(store_path = %narinfo.store_path, "serving NAR from the cache");
+    // ranges are only served when what's cached is served as is
+    file.seek(SeekFrom::Start(start)).await?;
+    let reader = if cached_compression == *compression { Box::new(file) } else { compression.encoder(file) };
+    return Ok((reader, start));
+  }
+
+  // the next alike path, or else the upstream, is tried when one doesn't match
+  for alike in alikes {
+    match rebuild(state, request, alike).await {
+      Ok(Some(rebuilt)) => {
+        tracing::info!(path = %alike.store_path, "serving NAR rebuilt from an alike path");
+        return Ok((compression.encoder(rebuilt), 0));
+      }
+      Ok(None) => {}
+      Err(e) => tracing::warn!(?e, path = %alike.store_path, "failed to rebuild NAR"),
+    }
   }
+
+  // the NAR comes from the same upstream as its narinfo, concurrent requests share the download
+  let open = {
+    let narinfo = narinfo.clone();
+    let passthrough = passthrough.clone();
+    let upstream = upstream.clone();
+    async move {
+      let Some(sr) = upstream.file(&narinfo.url).await? else {
+        return Ok(None);
+      };
+      let reader: compression::Reader = match passthrough {
+        // no need to decode, only to check what upstream sends
+        Some((file_hash, file_size)) => Box::new(VerifyingReader::new(sr, file_hash, file_size)?),
+        None => Box::new(VerifyingReader::new(Compression::of(&narinfo)?.decoder(sr), narinfo.nar_hash.clone(), narinfo.nar_size)?),
+      };
+      eyre::Ok(Some(reader))
+    }
+  };
+  let cached = state.nar_cache.get_or_download(&cached_hash, cached_compression, open).await?
+    .ok_or(anyhow!("nar not found upstream")).err_with_status(StatusCode::NOT_FOUND)?;
+  let reader = if cached_compression == *compression { cached } else { compression.encoder(cached) };
+  Ok((reader, 0))
+}
+
+/// Rebuilds the NAR of `request` from `alike` and checks it before anything is served, returns `None` if it doesn't match.
+///
+/// The NAR is rebuilt into the cache like downloads, which concurrent requests share, and read back from there. Mismatches are remembered.
+async fn rebuild(state: &MyState, request: &NarRequest, alike: &Alike) -> eyre::Result<Option<compression::Reader>> {
+  let narinfo = &request.narinfo;
+  let open = {
+    let alike = alike.clone();
+    let store_dir = state.store_dir.clone();
+    let narinfo = narinfo.clone();
+    async move {
+      let reader = VerifyingReader::new(alike.reconstruct(&store_dir), narinfo.nar_hash.clone(), narinfo.nar_size)?;
+      eyre::Ok(Some(Box::new(reader) as compression::Reader))
+    }
+  };
+  let mut rebuilt = state.nar_cache.get_or_download(&narinfo.nar_hash, Compression::None, open).await?
+    .ok_or(anyhow!("alike path not rebuilt"))?;
+  if let Err(e) = tokio::io::copy(&mut rebuilt, &mut tokio::io::sink()).await {
+    tracing::info!(error = %e, path = %alike.store_path, "alike path doesn't match");
+    state.db.insert_failed_alike(&request.url, &alike.store_path.to_string()).await?;
+    return Ok(None);
+  }
+
+  if let Some((file, _)) = state.nar_cache.get(&narinfo.nar_hash, Compression::None).await? {
+    return Ok(Some(Box::new(file)));
+  }
+  // too large for the cache, rebuilt again now that it's known to match
+  let reader = VerifyingReader::new(alike.reconstruct(&state.store_dir), narinfo.nar_hash.clone(), narinfo.nar_size)?;
+  Ok(Some(Box::new(reader)))
+}
+
+/// Answers from the narinfo, without downloading anything.
+async fn head_nar(State(state): State<MyState>, Path(params): Path<Params>) -> impl IntoResultReponse {
+  let request = NarRequest::new(&state, &params.path).await?;
+  let size = request.size(&state).await;
+  Ok(nar_response(Body::empty(), size, &ByteRange::Full))
+}
+
+/// Reads the bytes of `range` from `reader`, which is at `position`.
+async fn read_range(mut reader: compression::Reader, range: &ByteRange, position: u64) -> io::Result<compression::Reader> {
+  let ByteRange::Partial(range) = range else {
+    return Ok(reader);
+  };
+  tokio::io::copy(&mut (&mut reader).take(range.start - position), &mut tokio::io::sink()).await?;
+  Ok(Box::new(reader.take(range.end - range.start)))
+}
+
+fn nar_response(body: Body, size: Option<u64>, range: &ByteRange) -> (StatusCode, Response) {
+  let mut response = body.into_response();
+  let Some(size) = size else {
+    return (StatusCode::OK, response);
+  };
+
+  let headers = response.headers_mut();
+  headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+  match range {
+    ByteRange::Partial(range) => {
+      headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&format!("bytes {}-{}/{size}", range.start, range.end - 1)).expect("valid header"));
+      headers.insert(header::CONTENT_LENGTH, (range.end - range.start).into());
+      (StatusCode::PARTIAL_CONTENT, response)
+    }
+    _ => {
+      headers.insert(header::CONTENT_LENGTH, size.into());
+      (StatusCode::OK, response)
+    }
+  }
+}
+
+/// Returns the FileHash and FileSize of the upstream NAR, if it can be served as is in `compression`.
+fn passthrough(narinfo: &NarInfo, compression: Compression) -> Option<(NixHash, u64)> {
+  if narinfo.compression.as_deref() != Some(compression.name()) {
+    return None;
+  }
+  Some((narinfo.file_hash.clone()?, narinfo.file_size?))
 }
 
 async fn get_other(State(state): State<MyState>, Path(params): Path<Params>) -> impl IntoResultReponse {
+  if let Some(hash) = params.path.strip_suffix(".ls") {
+    let hash = store_hash(hash)?;
+    return get_listing(&state, hash).await;
+  }
   if ! params.path.ends_with(".narinfo") {
-    return Err(anyhow!("Only .narinfo files are supported").into());
+    return Err(anyhow!("Only .narinfo and .ls files are supported").into());
+  }
+
+  let hash = store_hash(params.path.trim_end_matches(".narinfo"))?;
+  let Some((mut narinfo, _)) = fetch_narinfo(&state, hash).await? else {
+    return Ok((axum::http::StatusCode::NOT_FOUND, "".into_response()));
+  };
+  let url = format!("{}.nar", narinfo.nar_hash.to_nix32());
+
+  state.db.insert_nar_url(&url, hash).await?;
+
+  // a NAR rebuilt locally may be served instead of downloading it, if there are installed paths to try to rebuild it from
+  let rebuilt = !find_alikes(&state, &url, &narinfo).await.is_empty();
+
+  // the file hash and size of transcoded NARs aren't known in advance
+  let (file_hash, file_size) = match passthrough(&narinfo, state.compression) {
+    _ if state.compression == Compression::None => (Some(narinfo.nar_hash.clone()), Some(narinfo.nar_size)),
+    Some((file_hash, file_size)) if !rebuilt => (Some(file_hash), Some(file_size)),
+    _ => (None, None),
+  };
+  narinfo.url = format!("nar/{url}{}", state.compression.extension());
+  narinfo.compression = Some(state.compression.name().to_owned());
+  narinfo.file_hash = file_hash;
+  narinfo.file_size = file_size;
+  if let Some(key) = &state.secret_key {
+    narinfo.sign(key);
+  }
+
+  let body = narinfo.to_string();
+
+  Ok((StatusCode::OK, IntoResponse::into_response(body)))
+}
+
+/// Lists the NAR we serve for the store path with the given hash, which may differ from the upstream one.
+async fn get_listing(state: &MyState, hash: &str) -> Result<(StatusCode, Response)> {
+  let Some((narinfo, upstream)) = fetch_narinfo(state, hash).await? else {
+    return Ok((StatusCode::NOT_FOUND, "".into_response()));
+  };
+  let url = format!("{}.nar", narinfo.nar_hash.to_nix32());
+  let request = NarRequest {
+    compression: Compression::None,
+    passthrough: passthrough(&narinfo, Compression::None),
+    alikes: find_alikes(state, &url, &narinfo).await,
+    url,
+    narinfo,
+    upstream,
+  };
+  let (reader, _) = open_nar(state, &request, 0).await?;
+  let listing = nar::listing(reader).await?;
+  Ok((StatusCode::OK, axum::Json(listing).into_response()))
+}
+
+/// Only tells whether the narinfo, or the store path of a listing, exists.
+async fn head_other(State(state): State<MyState>, Path(params): Path<Params>) -> impl IntoResultReponse {
+  let Some(hash) = params.path.strip_suffix(".narinfo").or_else(|| params.path.strip_suffix(".ls")) else {
+    return Err(anyhow!("Only .narinfo and .ls files are supported").into());
+  };
+  let hash = store_hash(hash)?;
+
+  let status = match fetch_narinfo(&state, hash).await? {
+    Some(_) => StatusCode::OK,
+    None => StatusCode::NOT_FOUND,
+  };
+  Ok((status, "".into_response()))
+}
+
+/// Checks that a requested store path hash is valid, before it's looked up, sent upstream or stored.
+fn store_hash(hash: &str) -> Result<&str> {
+  StorePath::parse_hash(hash).err_with_status(StatusCode::BAD_REQUEST)?;
+  Ok(hash)
+}
+
+/// Returns the narinfo of the store path with the given hash and the upstream it comes from, from the database or else from the first upstream which has it.
+///
+/// Narinfos without a valid signature from a key trusted for their upstream are rejected.
+async fn fetch_narinfo(state: &MyState, hash: &str) -> eyre::Result<Option<(NarInfo, Upstream)>> {
+  if let (Some(text), Some(url)) = (state.db.narinfo(hash).await?, state.db.upstream(hash).await?) {
+    // unless its upstream isn't configured anymore
+    if let Some(upstream) = state.upstreams.iter().find(|u| u.url == url) {
+      return Ok(Some((upstream.verify(&text)?, upstream.clone())));
+    }
   }
 
-  let r = reqwest::Client::new().get(format!("https://cache.nixos.org/{}", params.path)).send().await?;
-  if ! r.status().is_success() {
-    if r.status().as_u16() == StatusCode::NOT_FOUND {
-      return Ok((axum::http::StatusCode::NOT_FOUND, "".into_response()));
+  // no upstream had it not long ago
+  let missed = state.db.miss(hash).await?;
+  if missed.is_some_and(|m| m.elapsed().is_ok_and(|e| e < state.negative_ttl)) {
+    return Ok(None);
+  }
+
+  let mut error = None;
+  for upstream in state.upstreams.iter() {
+    match upstream.narinfo(hash).await {
+      Ok(Some((narinfo, text))) => {
+        state.db.insert_narinfo(hash, &text, &upstream.url).await?;
+        if missed.is_some() {
+          state.db.remove_miss(hash).await?;
+        }
+        return Ok(Some((narinfo, upstream.clone())));
+      }
+      Ok(None) => {}
+      Err(e) => {
+        tracing::warn!(?e, upstream = upstream.url, "failed to fetch narinfo");
+        error = Some(e);
+      }
+    }
+  }
+
+  // not found anywhere, unless an upstream failed to tell
+  match error {
+    Some(e) => Err(e),
+    None => {
+      if !state.negative_ttl.is_zero() {
+        state.db.insert_miss(hash).await?;
+      }
+      Ok(None)
     }
-    return Err(anyhow!("Failed to fetch narinfo from cache.nixos.org").into());
   }
-  let status = StatusCode::from_u16(u16::from(r.status())).unwrap();
+}
 
-  let text = r.text().await?; 
-  let mut data = text.lines().filter_map(|l| {
-    l.find(": ").map(|i| (&l[..i], l[i+2..].to_owned()))
-  }).collect::<HashMap<_,_>>();
+/// Proxies build logs, from "log/<drv>", and debug info, from "debuginfo/<build id>", of the first upstream which has them.
+async fn get_upstream_file(state: &MyState, path: &str) -> Result<(StatusCode, Response)> {
+  let mut error = None;
+  for upstream in state.upstreams.iter() {
+    match upstream.decoded_file(path).await {
+      Ok(Some(reader)) => return Ok((StatusCode::OK, Body::from_stream(ReaderStream::new(reader)).into_response())),
+      Ok(None) => {}
+      Err(e) => {
+        tracing::warn!(?e, upstream = upstream.url, path, "failed to fetch file");
+        error = Some(e);
+      }
+    }
+  }
+  match error {
+    Some(e) => Err(e.into()),
+    None => Ok((StatusCode::NOT_FOUND, "".into_response())),
+  }
+}
 
+async fn get_log(State(state): State<MyState>, Path(params): Path<Params>) -> impl IntoResultReponse {
+  get_upstream_file(&state, &format!("log/{}", params.path)).await
+}
 
-  state.data.write().await.insert(format!("{}.nar", data.get("NarHash").unwrap().split(":").nth(1).unwrap()), data.get("FileHash").unwrap().split(":").nth(1).unwrap().to_owned());
+async fn get_debuginfo(State(state): State<MyState>, Path(params): Path<Params>) -> impl IntoResultReponse {
+  get_upstream_file(&state, &format!("debuginfo/{}", params.path)).await
+}
 
-  data.remove("Compression");
-  data.insert("FileHash", data.get("NarHash").unwrap().to_owned());
-  data.insert("FileSize", data.get("NarSize").unwrap().to_owned());
-  data.insert("URL", format!("nar/{}.nar", data.get("NarHash").unwrap().split(":").nth(1).unwrap()));
+/// Forgets which narinfos upstreams didn't have, they'll be looked for again.
+///
+/// Like the other admin routes, it isn't authenticated: the server only listens on localhost, and a reverse proxy must not expose "/admin".
+async fn delete_misses(State(state): State<MyState>) -> Result<impl IntoResponse> {
+  let count = state.db.clear_misses().await?;
+  tracing::info!(count, "cleared the negative narinfo cache");
+  Ok(format!("{count}\n"))
+}
 
-  let body = data.into_iter().map(|(k, v)| {
-    format!("{}: {}", k, v)
-  }).collect::<Vec<_>>().join("\n") + "\n";
+async fn delete_miss(State(state): State<MyState>, Path(hash): Path<String>) -> Result<impl IntoResponse> {
+  let hash = store_hash(hash.trim_end_matches(".narinfo"))?;
+  if state.db.remove_miss(hash).await? {
+    Ok(StatusCode::NO_CONTENT)
+  } else {
+    Err(anyhow!("{hash} isn't in the negative narinfo cache")).err_with_status(StatusCode::NOT_FOUND)
+  }
+}
 
-  Ok((status, IntoResponse::into_response(body)))
+/// The installed paths the NAR at `url` may be rebuilt from, see `alike::find_alikes`, leaving out those it couldn't be rebuilt from.
+///
+/// Only picks candidates without reading them, they're checked by `rebuild`.
+async fn find_alikes(state: &MyState, url: &str, narinfo: &NarInfo) -> Vec<Alike> {
+  let result: eyre::Result<_> = async {
+    let same_name = {
+      let mut index = state.store_index.lock().await;
+      index.refresh(&state.store_dir).await?;
+      index.hashes(narinfo.store_path.name()).to_vec()
+    };
+    let dedup_hashes = match &state.dedup_db {
+      Some(dedup_db) => dedup_db.dedup_hashes(narinfo.store_path.hash(), &same_name).await.unwrap_or_else(|e| {
+        tracing::warn!(?e, "failed to look up dedup hashes");
+        HashMap::new()
+      }),
+      None => HashMap::new(),
+    };
+    let failed = state.db.failed_alikes(url).await?.iter().map(|p| p.parse()).collect::<eyre::Result<Vec<StorePath>>>()?;
+    let index = state.store_index.lock().await;
+    alike::find_alikes(&index, narinfo, &dedup_hashes, &failed)
+  }.await;
+  result.unwrap_or_else(|e| {
+    tracing::warn!(?e, "failed to look for alike paths");
+    Vec::new()
+  })
 }
 
-async fn nix_cache_info() -> Result<impl IntoResponse> {
-  Ok("StoreDir: /nix/store
+async fn nix_cache_info(State(state): State<MyState>) -> Result<impl IntoResponse> {
+  Ok(format!("StoreDir: {}
 WantMassQuery: 1
 Priority: 30
-")
+", state.store_dir))
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 struct MyState {
-  data: Arc<RwLock<HashMap<String, String>>>
+  store_dir: Arc<StoreDir>,
+  db: NarInfoDb,
+  secret_key: Option<Arc<SecretKey>>,
+  upstreams: Arc<Vec<Upstream>>, // by priority
+  compression: Compression,
+  nar_cache: NarCache,
+  negative_ttl: Duration,
+  store_index: Arc<Mutex<StoreIndex>>, // installed store paths, to find alike ones
+  dedup_db: Option<DedupDb>,
 }
 
 
-pub async fn http_server(state: MyState) -> io::Result<()> {
+async fn http_server(state: MyState) -> io::Result<()> {
     let app = Router::new()
         .route("/nix-cache-info", get(nix_cache_info))
-        .route("/nar/*path", get(get_nar))
-        .route("/*path", get(get_other))
+        // not authenticated, see delete_misses
+        .route("/admin/misses", delete(delete_misses))
+        .route("/admin/misses/:hash", delete(delete_miss))
+        .route("/nar/*path", get(get_nar).head(head_nar))
+        .route("/log/*path", get(get_log))
+        .route("/debuginfo/*path", get(get_debuginfo))
+        .route("/*path", get(get_other).head(head_other))
         .layer(TraceLayer::new_for_http()
           .make_span_with(|request: &Request<_>| {
             //let matched_path = request.extensions().get::<MatchedPath>().map(MatchedPath::as_str);
@@ -0,0 +1,134 @@
+//! Compressions of NARs in binary caches, as named by the `Compression` field of narinfos.
+
+use std::{fmt, str::FromStr};
+use async_compression::{Level, tokio::bufread::{BrotliDecoder, BrotliEncoder, BzDecoder, BzEncoder, GzipDecoder, GzipEncoder, XzDecoder, XzEncoder, ZstdDecoder, ZstdEncoder}};
+use color_eyre::eyre;
+use tokio::io::{AsyncBufRead, AsyncRead, BufReader};
+use crate::narinfo::NarInfo;
+
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+  #[default]
+  None,
+  Xz,
+  Zstd,
+  Bzip2,
+  Brotli,
+  Gzip,
+}
+
+impl Compression {
+  pub const ALL: [Self; 6] = [Self::None, Self::Xz, Self::Zstd, Self::Bzip2, Self::Brotli, Self::Gzip];
+
+  /// The compression of the NAR of `narinfo`, which is bzip2 when unspecified, like in Nix.
+  pub fn of(narinfo: &NarInfo) -> eyre::Result<Self> {
+    narinfo.compression.as_deref().unwrap_or("bzip2").parse()
+      .map_err(|e: eyre::Error| e.wrap_err(format!("can't decode the NAR of {}", narinfo.store_path)))
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::None => "none",
+      Self::Xz => "xz",
+      Self::Zstd => "zstd",
+      Self::Bzip2 => "bzip2",
+      Self::Brotli => "br",
+      Self::Gzip => "gzip",
+    }
+  }
+
+  /// Appended to ".nar" in NAR URLs, like Nix does.
+  pub fn extension(self) -> &'static str {
+    match self {
+      Self::None => "",
+      Self::Xz => ".xz",
+      Self::Zstd => ".zst",
+      Self::Bzip2 => ".bz2",
+      Self::Brotli => ".br",
+      Self::Gzip => ".gz",
+    }
+  }
+
+  /// Compresses `reader` on the fly.
+  pub fn encoder(self, reader: impl AsyncRead + Send + Unpin + 'static) -> Reader {
+    let reader = BufReader::new(reader);
+    match self {
+      Self::None => Box::new(reader),
+      Self::Xz => Box::new(XzEncoder::new(reader)),
+      Self::Zstd => Box::new(ZstdEncoder::new(reader)),
+      Self::Bzip2 => Box::new(BzEncoder::new(reader)),
+      Self::Brotli => Box::new(BrotliEncoder::with_quality(reader, Level::Precise(5))), // the default is way too slow to stream
+      Self::Gzip => Box::new(GzipEncoder::new(reader)),
+    }
+  }
+
+  /// Decompresses `reader` on the fly.
+  pub fn decoder(self, reader: impl AsyncBufRead + Send + Unpin + 'static) -> Reader {
+    match self {
+      Self::None => Box::new(reader),
+      Self::Xz => Box::new(XzDecoder::new(reader)),
+      Self::Zstd => Box::new(ZstdDecoder::new(reader)),
+      Self::Bzip2 => Box::new(BzDecoder::new(reader)),
+      Self::Brotli => Box::new(BrotliDecoder::new(reader)),
+      Self::Gzip => Box::new(GzipDecoder::new(reader)),
+    }
+  }
+}
+
+impl FromStr for Compression {
+  type Err = eyre::Error;
+
+  fn from_str(s: &str) -> eyre::Result<Self> {
+    Self::ALL.into_iter().find(|c| c.name() == s).ok_or_else(|| eyre::eyre!("unsupported compression: {s:?}"))
+  }
+}
+
+impl fmt::Display for Compression {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+  use tokio::io::AsyncReadExt;
+
+  #[tokio::test]
+  async fn roundtrip() -> eyre::Result<()> {
+    let data = b"nix-archive-1".repeat(1000);
+    for compression in Compression::ALL {
+      assert_eq!(compression.to_string().parse::<Compression>()?, compression);
+
+      let mut encoded = Vec::new();
+      compression.encoder(Cursor::new(data.clone())).read_to_end(&mut encoded).await?;
+      let mut decoded = Vec::new();
+      compression.decoder(Cursor::new(encoded)).read_to_end(&mut decoded).await?;
+      assert_eq!(decoded, data, "{compression}");
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn of() -> eyre::Result<()> {
+    let mut narinfo: NarInfo = "StorePath: /nix/store/3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1
+URL: nar/1ivslgrn7bi7cymqlw0q1gql6ds6xgxkvcfjlb2z31ma7i8s6s3g.nar.zst
+Compression: zstd
+NarHash: sha256:0hb5svmr2jxyqsnrc5n7sqgr3pp4p8cvzzc23j2pn0ar0h4zvx0s
+NarSize: 226560
+".parse()?;
+    assert_eq!(Compression::of(&narinfo)?, Compression::Zstd);
+
+    narinfo.compression = None;
+    assert_eq!(Compression::of(&narinfo)?, Compression::Bzip2);
+
+    narinfo.compression = Some("lzip".to_owned());
+    let error = Compression::of(&narinfo).unwrap_err();
+    assert_eq!(error.to_string(), "can't decode the NAR of 3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1");
+    assert_eq!(error.root_cause().to_string(), "unsupported compression: \"lzip\"");
+    Ok(())
+  }
+}
@@ -0,0 +1,49 @@
+//! Postgres table of the dedup hashes of store paths, see `dedup_hash_modulo`.
+//!
+//! download-and-compute-dedup-hashes fills it from upstream NARs, the substituter reads it to find alike store paths.
+
+use std::collections::HashMap;
+use color_eyre::eyre;
+use sqlx::{PgPool, Row};
+
+#[derive(Debug, Clone)]
+pub struct DedupDb {
+  pool: PgPool,
+}
+
+impl DedupDb {
+  /// Creates the table if needed.
+  pub async fn new(pool: PgPool) -> eyre::Result<Self> {
+    // dedup hashes are computed modulo the store path's own hash, in nix32
+    sqlx::query("create table if not exists dedup_hashes (store_hash char(32) primary key, dedup_hash char(52) not null)")
+      .execute(&pool).await?;
+    sqlx::query("create index if not exists dedup_hashes_dedup_hash on dedup_hashes (dedup_hash)")
+      .execute(&pool).await?;
+    Ok(Self { pool })
+  }
+
+  pub async fn insert(&self, store_hash: &str, dedup_hash: &[u8; 32]) -> eyre::Result<()> {
+    sqlx::query("insert into dedup_hashes (store_hash, dedup_hash) values ($1, $2) on conflict (store_hash) do update set dedup_hash = excluded.dedup_hash")
+      .bind(store_hash)
+      .bind(nix_base32::to_nix_base32(dedup_hash))
+      .execute(&self.pool).await?;
+    Ok(())
+  }
+
+  /// The known dedup hashes of `target` and `others`, and of all the store paths sharing the one of `target`, by store path hash.
+  pub async fn dedup_hashes(&self, target: &[u8; 32], others: &[[u8; 32]]) -> eyre::Result<HashMap<[u8; 32], String>> {
+    let hashes = std::iter::once(target).chain(others).map(|h| String::from_utf8_lossy(h).into_owned()).collect::<Vec<_>>();
+    let rows = sqlx::query("select store_hash, dedup_hash from dedup_hashes where store_hash = any($1) or dedup_hash = (select dedup_hash from dedup_hashes where store_hash = $2)")
+      .bind(&hashes)
+      .bind(&hashes[0])
+      .fetch_all(&self.pool).await?;
+
+    let mut dedup_hashes = HashMap::new();
+    for row in rows {
+      let store_hash = row.try_get::<&str, _>("store_hash")?;
+      let store_hash = store_hash.as_bytes().try_into().map_err(|_| eyre::eyre!("invalid store path hash {store_hash:?}"))?;
+      dedup_hashes.insert(store_hash, row.try_get::<String, _>("dedup_hash")?);
+    }
+    Ok(dedup_hashes)
+  }
+}
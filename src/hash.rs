@@ -0,0 +1,192 @@
+use std::{fmt, str::FromStr};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use color_eyre::eyre;
+
+const NIX32_ALPHABET: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgo {
+  Md5,
+  Sha1,
+  Sha256,
+  Sha512,
+}
+
+impl HashAlgo {
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Md5 => "md5",
+      Self::Sha1 => "sha1",
+      Self::Sha256 => "sha256",
+      Self::Sha512 => "sha512",
+    }
+  }
+
+  /// Size of the digest, in bytes.
+  pub fn size(self) -> usize {
+    match self {
+      Self::Md5 => 16,
+      Self::Sha1 => 20,
+      Self::Sha256 => 32,
+      Self::Sha512 => 64,
+    }
+  }
+}
+
+impl FromStr for HashAlgo {
+  type Err = eyre::Error;
+
+  fn from_str(s: &str) -> eyre::Result<Self> {
+    match s {
+      "md5" => Ok(Self::Md5),
+      "sha1" => Ok(Self::Sha1),
+      "sha256" => Ok(Self::Sha256),
+      "sha512" => Ok(Self::Sha512),
+      _ => Err(eyre::eyre!("unknown hash algorithm: {s:?}")),
+    }
+  }
+}
+
+/// A hash and its algorithm, which Nix prints as "sha256:<nix32>" in narinfos.
+///
+/// Parses "<algo>:<digest>", with the digest in nix base32, hex or base64, and SRI hashes: "<algo>-<base64>".
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NixHash {
+  algo: HashAlgo,
+  digest: Vec<u8>,
+}
+
+impl NixHash {
+  pub fn new(algo: HashAlgo, digest: impl Into<Vec<u8>>) -> eyre::Result<Self> {
+    let digest = digest.into();
+    if digest.len() != algo.size() {
+      return Err(eyre::eyre!("{} digest must be {} bytes long, got {}", algo.name(), algo.size(), digest.len()));
+    }
+    Ok(Self { algo, digest })
+  }
+
+  pub fn sha256(digest: [u8; 32]) -> Self {
+    Self { algo: HashAlgo::Sha256, digest: digest.to_vec() }
+  }
+
+  pub fn algo(&self) -> HashAlgo {
+    self.algo
+  }
+
+  pub fn digest(&self) -> &[u8] {
+    &self.digest
+  }
+
+  /// The digest alone, in nix base32.
+  pub fn to_nix32(&self) -> String {
+    nix_base32::to_nix_base32(&self.digest)
+  }
+
+  /// The digest alone, in hex.
+  pub fn to_hex(&self) -> String {
+    hex::encode(&self.digest)
+  }
+
+  /// "<algo>-<base64>"
+  pub fn to_sri(&self) -> String {
+    format!("{}-{}", self.algo.name(), BASE64.encode(&self.digest))
+  }
+}
+
+impl FromStr for NixHash {
+  type Err = eyre::Error;
+
+  fn from_str(s: &str) -> eyre::Result<Self> {
+    let invalid = || eyre::eyre!("invalid hash: {s:?}");
+
+    if let Some((algo, digest)) = s.split_once('-').filter(|(algo, _)| !algo.contains(':')) {
+      let algo: HashAlgo = algo.parse()?;
+      let digest = BASE64.decode(digest).map_err(|_| invalid())?;
+      return Self::new(algo, digest);
+    }
+
+    let (algo, digest) = s.split_once(':').ok_or_else(|| eyre::eyre!("hash without algorithm: {s:?}"))?;
+    let algo: HashAlgo = algo.parse()?;
+    let size = algo.size();
+    // the encoding is told apart by the length, like Nix does
+    let digest = if digest.len() == size * 2 {
+      hex::decode(digest).ok()
+    } else if digest.len() == (size * 8).div_ceil(5) {
+      nix32_decode(digest, size)
+    } else if digest.len() == size.div_ceil(3) * 4 {
+      BASE64.decode(digest).ok()
+    } else {
+      None
+    };
+    Self::new(algo, digest.ok_or_else(invalid)?)
+  }
+}
+
+impl fmt::Display for NixHash {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.algo.name(), self.to_nix32())
+  }
+}
+
+/// Decodes `size` bytes from nix base32, which is little endian and read from the last char.
+pub fn nix32_decode(s: &str, size: usize) -> Option<Vec<u8>> {
+  if s.len() != (size * 8).div_ceil(5) {
+    return None;
+  }
+
+  let mut bytes = vec![0u8; size];
+  for (n, c) in s.bytes().rev().enumerate() {
+    let digit = NIX32_ALPHABET.iter().position(|a| *a == c)? as u16;
+    let (i, j) = (n * 5 / 8, n * 5 % 8);
+    let shifted = digit << j;
+    bytes[i] |= shifted as u8;
+    let carry = (shifted >> 8) as u8;
+    if i + 1 < size {
+      bytes[i + 1] |= carry;
+    } else if carry != 0 {
+      return None; // more bits than the digest can hold
+    }
+  }
+  Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sha2::{Digest, Sha256};
+
+  #[test]
+  fn nix32() -> eyre::Result<()> {
+    // nix-hash --type sha256 --to-base32 $(echo -n hello | sha256sum)
+    let digest: [u8; 32] = Sha256::digest(b"hello").into();
+    let hash = NixHash::sha256(digest);
+    assert_eq!(hash.to_string(), "sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic");
+    assert_eq!(hash.to_string().parse::<NixHash>()?, hash);
+
+    assert!("sha256:z94qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic".parse::<NixHash>().is_err()); // overflows
+    assert!("sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwi".parse::<NixHash>().is_err());
+    assert!("sha256:e94qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic".parse::<NixHash>().is_err());
+    assert!("sha3:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic".parse::<NixHash>().is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn encodings() -> eyre::Result<()> {
+    let hash: NixHash = "sha256:094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic".parse()?;
+    assert_eq!(hash.to_hex(), "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+    assert_eq!(hash.to_sri(), "sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=");
+
+    for s in [
+      "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+      "sha256:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=",
+      "sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=",
+    ] {
+      assert_eq!(s.parse::<NixHash>()?, hash, "{s}");
+    }
+
+    assert!("sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmC=".parse::<NixHash>().is_err());
+    assert!("sha1-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=".parse::<NixHash>().is_err());
+    assert!("sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b982g".parse::<NixHash>().is_err());
+    Ok(())
+  }
+}
@@ -1,17 +1,38 @@
-mod store_path_automaton;
+pub mod alike;
+pub mod compression;
+pub mod dedup_db;
+pub mod hash;
+pub mod nar;
+pub mod nar_cache;
+pub mod narinfo;
+pub mod narinfo_db;
+pub mod range;
+pub mod references;
+pub mod signing;
+pub mod store_path;
+pub mod store_path_automaton;
+pub mod upstream;
 
-use std::{borrow::Cow, pin::Pin, task::{Context, Poll}, io, collections::HashMap};
-use axum::{http::Request, extract::MatchedPath};
+use std::{pin::Pin, task::{Context, Poll, ready}, io, collections::HashMap};
 use color_eyre::eyre;
+use hash::{HashAlgo, NixHash};
+use pin_project_lite::pin_project;
 use sha2::{Sha256, Digest};
-use tokio::io::{AsyncWrite, AsyncRead, AsyncReadExt, AsyncWriteExt};
-use tower_http::trace::TraceLayer;
-use tracing::{info_span, Span};
+use tokio::io::{AsyncWrite, AsyncRead, AsyncWriteExt, ReadBuf};
+use store_path::StoreDir;
+use store_path_automaton::{ReplacementReport, ReportingRewriter, StorePathAutomaton, StorePathReader, UnknownHashError};
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter, Registry, Layer, layer::SubscriberExt, util::SubscriberInitExt};
 
-const REPL_PATH_LEN : usize = 44;
-const CHUNK : usize = 50; // MUST be bigger than REPL_PATH_LEN
+const BUF_SIZE: usize = 1024 * 1024; // large buffers amortize the per-read overhead on multi-GB NARs
+
+/// What every store path hash is replaced with before computing a dedup hash.
+/// "e" isn't part of the nix base32 alphabet, so a masked store path can never be mistaken for a real one.
+pub const DEDUP_PLACEHOLDER: [u8; 32] = [b'e'; 32];
+
+/// What the hash of the NAR's own store path is replaced with when computing a dedup hash modulo it.
+/// Like Nix's `HashModuloSink`, self-references are replaced with NUL bytes.
+pub const DEDUP_SELF_PLACEHOLDER: [u8; 32] = [0; 32];
 
 pub fn setup_logging() -> eyre::Result<()> {
   color_eyre::install()?;
@@ -40,6 +61,12 @@ pub struct AsyncSha256Hasher {
   hasher: Sha256
 }
 
+impl Default for AsyncSha256Hasher {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl AsyncSha256Hasher {
   pub fn new() -> Self {
     Self {
@@ -62,113 +89,359 @@ impl AsyncWrite for AsyncSha256Hasher {
     Poll::Ready(Ok(()))
   }
 
-  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+  fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
     Poll::Ready(Ok(()))
   }
 }
 
+pin_project! {
+  /// Passes a NAR through while checking it against its expected `NarHash` and `NarSize`.
+  ///
+  /// The hash is checked as soon as the expected size is reached, before the last bytes are returned,
+  /// so that a mismatching NAR always ends with an error instead of being complete.
+  /// No NAR is empty, so an expected size of 0, which would never be reached, is rejected.
+  pub struct VerifyingReader<R> {
+    #[pin]
+    reader: R,
+    hasher: Sha256,
+    nar_hash: NixHash,
+    nar_size: u64,
+    read: u64,
+  }
+}
 
-pub async fn replace_nix_paths(mut reader: impl AsyncRead + Unpin, mut writer: impl AsyncWrite + Unpin, replacements: HashMap<[u8;32], [u8;32]>) -> eyre::Result<bool> {
-  // the first CHUNK bytes contains the current chunk
-  // the last REPL_PATH_LEN bytes contains the beginning of the next chunk
-  // this is necessary to be able to search and replace over the chunk boundary
-  let mut buf = [0; CHUNK + REPL_PATH_LEN ];
-  // the first CHUNK bytes contains the next chunk
-  // the last REPL_PATH_LEN bytes contains the begining the current chunk potentially modified by the search and replace of the previous loop cycle
-  let mut buf_ahead = [0; CHUNK + REPL_PATH_LEN ];
+impl<R: AsyncRead> VerifyingReader<R> {
+  pub fn new(reader: R, nar_hash: NixHash, nar_size: u64) -> eyre::Result<Self> {
+    if nar_hash.algo() != HashAlgo::Sha256 {
+      return Err(eyre::eyre!("NAR hashes must be sha256, got {}", nar_hash.algo().name()));
+    }
+    if nar_size == 0 {
+      return Err(eyre::eyre!("NAR sizes can't be 0"));
+    }
+    Ok(Self { reader, hasher: Sha256::new(), nar_hash, nar_size, read: 0 })
+  }
+}
 
-  let mut buf_l;
-  let mut buf_ahead_l;
+impl<R: AsyncRead> AsyncRead for VerifyingReader<R> {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let this = self.project();
+    let filled = buf.filled().len();
+    ready!(this.reader.poll_read(cx, buf))?;
+    let new = &buf.filled()[filled..];
 
-  // nix store path with base32 hash: digit and alphabet without "eout"
-  let regex = regex::bytes::Regex::new(r"/nix/store/[0-9abcdfghijklmnpqrsvwxyz]{32}\-")?;
+    if new.is_empty() && buf.remaining() > 0 {
+      if *this.read < *this.nar_size {
+        return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("NAR too short: expected {} bytes, got {}", this.nar_size, this.read))));
+      }
+      return Poll::Ready(Ok(()));
+    }
 
-  // read first chunk to the first part of the buffer
-  // read multiple times until the chunk is filled or the end of the stream is reached (l == 0)
-  buf_l = 0;
-  loop {
-    let l = reader.read(&mut buf[buf_l..CHUNK]).await?;
-    buf_l += l;
-    if l == 0 || buf_l == CHUNK {
-      break;
+    if *this.read + new.len() as u64 > *this.nar_size {
+      buf.set_filled(filled);
+      return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, format!("NAR too long: expected {} bytes", this.nar_size))));
     }
-  }
-  // Artifically setup the second part of the ahead buffer with the first REPL_PATH_LEN bytes from buf.
-  // Those bytes are later used to carry overlapping replacements over from the last chunk to the next.
-  buf_ahead[CHUNK..].copy_from_slice(&buf[..REPL_PATH_LEN]);
-
-  loop {
-    // Here, we assume that the first CHUNK bytes of buf are available
-    // and that the last REPL_PATH_LEN bytes of buf_ahead contains what might need to be carried over from the search and replace of the last chunk.
-
-    // read ahead the next chunk to the first part of the ahead buffer
-    // read multiple times until the chunk is filled or the end of the stream is reached (l == 0)
-    buf_ahead_l = 0;
-    loop {
-      let l = reader.read(&mut buf_ahead[buf_ahead_l..CHUNK]).await?;
-      buf_ahead_l += l;
-      if l == 0 || buf_ahead_l == CHUNK {
-        break;
+    this.hasher.update(new);
+    *this.read += new.len() as u64;
+
+    if *this.read == *this.nar_size {
+      let hash = NixHash::sha256(this.hasher.clone().finalize().into());
+      if hash != *this.nar_hash {
+        buf.set_filled(filled);
+        return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, format!("NAR hash mismatch: expected {}, got {hash}", this.nar_hash))));
       }
     }
-    
-    // Complete the second part of the buffer with the beginning of the next chunk.
-    buf[CHUNK..].copy_from_slice(&buf_ahead[..REPL_PATH_LEN]);
-
-    // Carry over the last REPL_PATH_LEN bytes from the last chunk (saved in the the second part of the ahead buffer).
-    // This is necessary because the regex replacement might overlap the chunk boundary.
-    buf[..REPL_PATH_LEN].copy_from_slice(&buf_ahead[CHUNK..]);
-    
-    // length of available data to process
-    let proc_l = buf_l + (buf_ahead_l).min(REPL_PATH_LEN);
-
-    // actual search and replace.
-    let hash_offsets = regex.find_iter(&buf[..proc_l]).map(|m| m.start() + 11).collect::<Vec<_>>();
-    for hash_offset in hash_offsets {
-      let b: &[u8; 32] = &buf[hash_offset .. hash_offset + 32].try_into().unwrap();
-      if let Some(r) = replacements.get(b) {
-        buf[hash_offset .. hash_offset + 32].copy_from_slice(r);
+
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// Computes the dedup hash of what's written to it: the SHA-256 of the data where every store path hash is replaced by `DEDUP_PLACEHOLDER`.
+///
+/// Two NARs with the same dedup hash only differ by their store path hashes, so one can be turned into the other by rewriting them.
+///
+/// When the hash of the NAR's own store path is known, references to itself are replaced by `DEDUP_SELF_PLACEHOLDER` instead
+/// so that a NAR referencing itself and one referencing another path with the same name don't share a dedup hash.
+pub struct AsyncDedupHasher {
+  hasher: Sha256,
+  automaton: StorePathAutomaton,
+  self_hash: Option<[u8; 32]>,
+  pending: Vec<u8>, // the last bytes written, held back until we know they aren't part of a store path hash
+}
+
+impl Default for AsyncDedupHasher {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl AsyncDedupHasher {
+  pub fn new() -> Self {
+    Self::with_store_dir(&StoreDir::default(), None)
+  }
+
+  /// A hasher computing the dedup hash modulo `self_hash`, the hash of the NAR's own store path.
+  pub fn with_self_hash(self_hash: [u8; 32]) -> Self {
+    Self::with_store_dir(&StoreDir::default(), Some(self_hash))
+  }
+
+  pub fn with_store_dir(store_dir: &StoreDir, self_hash: Option<[u8; 32]>) -> Self {
+    Self {
+      hasher: Sha256::new(),
+      automaton: StorePathAutomaton::with_store_dir(store_dir),
+      self_hash,
+      pending: Vec::new(),
+    }
+  }
+
+  pub fn finalize(mut self) -> [u8; 32] {
+    self.hasher.update(&self.pending);
+    self.hasher.finalize().into()
+  }
+}
+
+impl AsyncWrite for AsyncDedupHasher {
+  fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+    let this = &mut *self;
+    let mut i = this.pending.len();
+    this.pending.extend_from_slice(buf);
+
+    while let Some(m) = this.automaton.find(&this.pending[i..]) {
+      // the automaton stops on the "-" following the hash
+      let hash = &mut this.pending[i + m - 32..i + m];
+      if this.self_hash.is_some_and(|h| h == *hash) {
+        hash.copy_from_slice(&DEDUP_SELF_PLACEHOLDER);
       } else {
-        return Ok(false);
+        hash.copy_from_slice(&DEDUP_PLACEHOLDER);
       }
+      i += m + 1;
     }
 
-    if buf_ahead_l == CHUNK { // if there's more chunks to read
-      writer.write_all(&buf[..CHUNK]).await?; // write one chunk
-    } else {
-      writer.write_all(&buf[..proc_l]).await?; // write everything that's been processed. might be smaller or bigger than CHUNK
-      break
-    };
+    // only the last 32 bytes can still be part of a store path hash
+    let ready = this.pending.len().saturating_sub(32);
+    this.hasher.update(&this.pending[..ready]);
+    this.pending.drain(..ready);
 
-    // swap buffers and their respective lengths
-    std::mem::swap(&mut buf, &mut buf_ahead);
-    std::mem::swap(&mut buf_l, &mut buf_ahead_l);
+    Poll::Ready(Ok(buf.len()))
   }
 
+  fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// Computes the dedup hash of a NAR, see `AsyncDedupHasher`.
+pub async fn dedup_hash(store_dir: &StoreDir, mut reader: impl AsyncRead + Unpin) -> eyre::Result<[u8; 32]> {
+  let mut hasher = AsyncDedupHasher::with_store_dir(store_dir, None);
+  tokio::io::copy(&mut reader, &mut hasher).await?;
+  Ok(hasher.finalize())
+}
+
+/// Computes the dedup hash of a NAR modulo the hash of its own store path, see `AsyncDedupHasher`.
+pub async fn dedup_hash_modulo(store_dir: &StoreDir, mut reader: impl AsyncRead + Unpin, self_hash: [u8; 32]) -> eyre::Result<[u8; 32]> {
+  let mut hasher = AsyncDedupHasher::with_store_dir(store_dir, Some(self_hash));
+  tokio::io::copy(&mut reader, &mut hasher).await?;
+  Ok(hasher.finalize())
+}
+
+/// Copies `reader` to `writer` while rewriting every store path hash according to `replacements`.
+///
+/// Returns `Ok(false)` as soon as a store path hash is missing from `replacements`,
+/// in which case only part of the output has been written to `writer`.
+pub async fn replace_nix_paths(store_dir: &StoreDir, reader: impl AsyncRead + Unpin, mut writer: impl AsyncWrite + Unpin, replacements: HashMap<[u8;32], [u8;32]>) -> eyre::Result<bool> {
+  let mut reader = StorePathReader::with_store_dir(store_dir, BUF_SIZE, reader, replacements);
+
+  match tokio::io::copy_buf(&mut reader, &mut writer).await {
+    Ok(_) => {}
+    Err(e) if UnknownHashError::from_io(&e).is_some() => return Ok(false),
+    Err(e) => return Err(e.into()),
+  }
+  writer.flush().await?;
+
   Ok(true)
 }
 
+/// Copies `reader` to `writer` while rewriting every store path hash found in `replacements`.
+///
+/// Unlike `replace_nix_paths`, the whole stream is always processed: hashes missing from `replacements` are left untouched
+/// and listed in the returned report along with their offsets, so that callers can tell how far the input is from being fully rewritable.
+pub async fn replace_nix_paths_report(store_dir: &StoreDir, reader: impl AsyncRead + Unpin, mut writer: impl AsyncWrite + Unpin, replacements: HashMap<[u8;32], [u8;32]>) -> eyre::Result<ReplacementReport> {
+  let mut reader = StorePathReader::with_store_dir(store_dir, BUF_SIZE, reader, ReportingRewriter::new(replacements));
+
+  tokio::io::copy_buf(&mut reader, &mut writer).await?;
+  writer.flush().await?;
+
+  Ok(reader.into_rewriter().into_report())
+}
+
 
 #[cfg(test)]
 mod tests {
   use super::*;
-  use tokio::io::{AsyncRead, AsyncWrite};
   use std::io::Cursor;
 
-  fn helper() {
+  // TODO write a structure-aware custom mutator for fuzzing
+
+  #[tokio::test]
+  async fn test_verifying_reader() -> eyre::Result<()> {
+    use tokio::io::AsyncReadExt;
 
-  }
+    let nar = b"nix-archive-1 or something like it";
+    let hash = NixHash::sha256(Sha256::digest(nar).into());
+    let read = |data: &'static [u8], size| {
+      let hash = hash.clone();
+      async move {
+        let mut out = Vec::new();
+        let r = VerifyingReader::new(data, hash, size)?.read_to_end(&mut out).await;
+        eyre::Ok((r, out))
+      }
+    };
 
-  // TODO write a structure-aware custom mutator for fuzzing
+    let (r, out) = read(nar, nar.len() as u64).await?;
+    assert!(r.is_ok());
+    assert_eq!(out, nar);
+
+    // the last bytes are never returned when the NAR doesn't match
+    let (r, out) = read(b"nix-archive-1 or something like iT", nar.len() as u64).await?;
+    assert!(r.is_err());
+    assert!(out.len() < nar.len());
+
+    assert!(read(&nar[..10], nar.len() as u64).await?.0.is_err());
+    assert!(read(nar, nar.len() as u64 - 1).await?.0.is_err());
+    assert!(read(nar, nar.len() as u64 + 1).await?.0.is_err());
+    assert!(read(b"", 0).await.is_err());
+    Ok(())
+  }
 
   #[tokio::test]
   async fn test_replace_nix_paths() -> eyre::Result<()> {
     let mut r = Cursor::new(b"abc /nix/store/abcdfghijklmnpqrsvwxyz0000000000- abc");
     let mut w = Vec::new();
     let mut repl = HashMap::new();
-    repl.insert(b"abcdfghijklmnpqrsvwxyz0000000000".to_owned().try_into().unwrap(), b"00000000000000000000000000000000".to_owned().try_into().unwrap());
-    let r = replace_nix_paths(&mut r, &mut w, repl).await?;
+    repl.insert(*b"abcdfghijklmnpqrsvwxyz0000000000", *b"00000000000000000000000000000000");
+    let r = replace_nix_paths(&StoreDir::default(), &mut r, &mut w, repl).await?;
+    assert!(r);
     assert_eq!(w, b"abc /nix/store/00000000000000000000000000000000- abc");
     Ok(())
   }
+
+  #[tokio::test]
+  async fn test_replace_nix_paths_report() -> eyre::Result<()> {
+    let mut r = Cursor::new(b"/nix/store/abcdfghijklmnpqrsvwxyz0000000000- /nix/store/11111111111111111111111111111111- /nix/store/abcdfghijklmnpqrsvwxyz0000000000- /nix/store/11111111111111111111111111111111-");
+    let mut w = Vec::new();
+    let mut repl = HashMap::new();
+    repl.insert(*b"abcdfghijklmnpqrsvwxyz0000000000", *b"00000000000000000000000000000000");
+    let report = replace_nix_paths_report(&StoreDir::default(), &mut r, &mut w, repl).await?;
+    assert_eq!(w, b"/nix/store/00000000000000000000000000000000- /nix/store/11111111111111111111111111111111- /nix/store/00000000000000000000000000000000- /nix/store/11111111111111111111111111111111-");
+    assert_eq!(report.replaced, 2);
+    assert_eq!(report.unknown.into_iter().collect::<Vec<_>>(), vec![(*b"11111111111111111111111111111111", vec![56, 146])]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_dedup_hash() -> eyre::Result<()> {
+    let a = b"abc /nix/store/abcdfghijklmnpqrsvwxyz0000000000-foo /nix/store/11111111111111111111111111111111-bar";
+    let b = b"abc /nix/store/00000000000000000000000000000000-foo /nix/store/22222222222222222222222222222222-bar";
+    let c = b"abc /nix/store/00000000000000000000000000000000-foo /nix/store/22222222222222222222222222222222-baz";
+
+    let hash_a = dedup_hash(&StoreDir::default(), Cursor::new(a)).await?;
+    assert_eq!(hash_a, dedup_hash(&StoreDir::default(), Cursor::new(b)).await?);
+    assert_ne!(hash_a, dedup_hash(&StoreDir::default(), Cursor::new(c)).await?);
+
+    let masked = b"abc /nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-foo /nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-bar";
+    let mut hasher = AsyncSha256Hasher::new();
+    hasher.write_all(masked).await?;
+    assert_eq!(hash_a, hasher.finalize());
+
+    // store paths split across writes
+    let mut hasher = AsyncDedupHasher::new();
+    for byte in a {
+      hasher.write_all(&[*byte]).await?;
+    }
+    assert_eq!(hash_a, hasher.finalize());
+
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_dedup_hash_modulo() -> eyre::Result<()> {
+    let self_a = *b"abcdfghijklmnpqrsvwxyz0000000000";
+    let self_b = *b"00000000000000000000000000000000";
+    let a = b"/nix/store/abcdfghijklmnpqrsvwxyz0000000000-foo/lib /nix/store/11111111111111111111111111111111-bar";
+    let b = b"/nix/store/00000000000000000000000000000000-foo/lib /nix/store/22222222222222222222222222222222-bar";
+    // references another path with the same name instead of itself
+    let c = b"/nix/store/33333333333333333333333333333333-foo/lib /nix/store/22222222222222222222222222222222-bar";
+
+    let hash_a = dedup_hash_modulo(&StoreDir::default(), Cursor::new(a), self_a).await?;
+    assert_eq!(hash_a, dedup_hash_modulo(&StoreDir::default(), Cursor::new(b), self_b).await?);
+    assert_ne!(hash_a, dedup_hash_modulo(&StoreDir::default(), Cursor::new(c), self_b).await?);
+
+    // without the self hash, both look the same
+    assert_eq!(dedup_hash(&StoreDir::default(), Cursor::new(a)).await?, dedup_hash(&StoreDir::default(), Cursor::new(c)).await?);
+
+    Ok(())
+  }
+
+  /// Reference implementation: a regex search and replace over the whole input at once.
+  fn replace_nix_paths_regex(store_dir: &str, input: &[u8], replacements: &HashMap<[u8;32], [u8;32]>) -> Option<Vec<u8>> {
+    let regex = regex::bytes::Regex::new(&format!(r"{}/[0-9abcdfghijklmnpqrsvwxyz]{{32}}\-", regex::escape(store_dir))).unwrap();
+    let hash_offset = store_dir.len() + 1;
+    let mut output = input.to_vec();
+    for m in regex.find_iter(input) {
+      let hash: &[u8; 32] = input[m.start() + hash_offset..m.start() + hash_offset + 32].try_into().unwrap();
+      output[m.start() + hash_offset..m.start() + hash_offset + 32].copy_from_slice(replacements.get(hash)?);
+    }
+    Some(output)
+  }
+
+  #[tokio::test]
+  async fn replace_nix_paths_matches_regex() -> eyre::Result<()> {
+    let hashes: [&[u8; 32]; 4] = [
+      b"abcdfghijklmnpqrsvwxyz0000000000",
+      b"nix00000000000000000000000000000",
+      b"11111111111111111111111111111111",
+      b"zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz",
+    ];
+    let mut repl = HashMap::new();
+    for (i, h) in hashes.iter().enumerate().skip(1) {
+      repl.insert(**h, *hashes[i - 1]);
+    }
+
+    // xorshift, so that failures are reproducible
+    let mut seed: u64 = 0x9e3779b97f4a7c15;
+    let mut rand = move || { seed ^= seed << 13; seed ^= seed >> 7; seed ^= seed << 17; seed };
+
+    // "/a/a" and "/0/1/0" overlap with themselves, and with the hash alphabet for the latter
+    for store_dir in ["/nix/store", "/gnu/store", "/a/a", "/0/1/0"] {
+      let prefix = format!("{store_dir}/");
+      let half = &prefix[..prefix.len() / 2];
+      let tokens: [&[u8]; 11] = [prefix.as_bytes(), half.as_bytes(), b"/nix/", b"/", b"nix", b"-", b"e", b"0", b"\0", hashes[0], hashes[1]];
+
+      for _ in 0..500 {
+        let mut input = Vec::new();
+        for _ in 0..rand() % 64 {
+          input.extend_from_slice(tokens[rand() as usize % tokens.len()]);
+          if rand() % 8 == 0 {
+            input.extend_from_slice(prefix.as_bytes());
+            input.extend_from_slice(hashes[1 + rand() as usize % 3]);
+            input.push(b'-');
+          }
+        }
+
+        let mut w = Vec::new();
+        let r = replace_nix_paths(&store_dir.parse()?, Cursor::new(&input), &mut w, repl.clone()).await?;
+        match replace_nix_paths_regex(store_dir, &input, &repl) {
+          Some(expected) => {
+            assert!(r, "input: {:?}", String::from_utf8_lossy(&input));
+            assert_eq!(w, expected, "input: {:?}", String::from_utf8_lossy(&input));
+          }
+          None => assert!(!r, "input: {:?}", String::from_utf8_lossy(&input)),
+        }
+      }
+    }
+    Ok(())
+  }
 }
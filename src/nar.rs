@@ -0,0 +1,710 @@
+//! Streaming NAR (Nix ARchive) parser and serializer, byte-exact with `nix-store --dump`.
+//!
+//! A NAR is a sequence of length-prefixed strings, each padded with zeros to a multiple of 8 bytes:
+//! ```text
+//! nar       = "nix-archive-1" node
+//! node      = "(" "type" ( regular | symlink | directory ) ")"
+//! regular   = "regular" [ "executable" "" ] "contents" contents
+//! symlink   = "symlink" "target" target
+//! directory = "directory" { "entry" "(" "name" name "node" node ")" }
+//! ```
+//! Directory entries are sorted by name.
+
+use std::{ffi::OsStr, future::Future, io, os::unix::{ffi::OsStrExt, fs::PermissionsExt}, path::{Path, PathBuf}, pin::Pin, task::{Context, Poll, ready}};
+use serde_json::json;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, DuplexStream, ReadBuf}, task::JoinHandle};
+
+const MAGIC: &[u8] = b"nix-archive-1";
+const MAX_NAME_LEN: u64 = 4096; // also used for symlink targets and tokens, like PATH_MAX
+
+/// A file, directory or symlink of a NAR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+  pub path: PathBuf, // relative to the root of the NAR, empty for the root itself
+  pub name_offset: Option<u64>, // offset of the name in the NAR, `None` for the root
+  pub node: Node,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+  Directory,
+  Regular {
+    executable: bool,
+    size: u64,
+    offset: u64, // offset of the contents in the NAR
+  },
+  Symlink {
+    target: PathBuf,
+    offset: u64, // offset of the target in the NAR
+  },
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn padding(len: u64) -> usize {
+  ((8 - len % 8) % 8) as usize
+}
+
+/// Checks a directory entry name the way Nix does.
+fn check_name(name: &[u8]) -> io::Result<()> {
+  if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') || name.contains(&0) {
+    return Err(invalid_data(format!("invalid NAR entry name {:?}", String::from_utf8_lossy(name))));
+  }
+  Ok(())
+}
+
+#[derive(Debug)]
+enum DecoderState {
+  Start,
+  Node, // the last entry has been read entirely, or it's a directory whose entries follow
+  Contents { remaining: u64, size: u64 }, // in the contents of a regular file
+  Done,
+}
+
+#[derive(Debug)]
+struct Directory {
+  path: PathBuf,
+  last_name: Option<Vec<u8>>,
+}
+
+/// Reads the entries of a NAR one by one, in the order they are stored.
+///
+/// The contents of regular files can be read with `contents` right after `next_entry` returned them,
+/// they are skipped otherwise.
+pub struct NarDecoder<R> {
+  reader: R,
+  offset: u64, // number of bytes read so far
+  state: DecoderState,
+  stack: Vec<Directory>, // directories we're in
+}
+
+impl<R: AsyncRead + Unpin> NarDecoder<R> {
+  pub fn new(reader: R) -> Self {
+    Self {
+      reader,
+      offset: 0,
+      state: DecoderState::Start,
+      stack: Vec::new(),
+    }
+  }
+
+  /// Number of bytes of the NAR read so far.
+  pub fn offset(&self) -> u64 {
+    self.offset
+  }
+
+  pub fn into_inner(self) -> R {
+    self.reader
+  }
+
+  pub async fn next_entry(&mut self) -> io::Result<Option<Entry>> {
+    match self.state {
+      DecoderState::Start => {
+        self.expect(MAGIC).await?;
+        return self.read_node(PathBuf::new(), None).await.map(Some);
+      }
+      DecoderState::Done => return Ok(None),
+      DecoderState::Contents { remaining, size } => {
+        self.skip(remaining).await?;
+        self.read_padding(size).await?;
+        self.state = DecoderState::Node;
+        self.expect(b")").await?;
+        self.end_entry().await?;
+      }
+      DecoderState::Node => {}
+    }
+
+    loop {
+      let Some(dir) = self.stack.last() else {
+        self.state = DecoderState::Done;
+        return Ok(None);
+      };
+      let dir_path = dir.path.clone();
+
+      match self.read_str(MAX_NAME_LEN).await?.as_slice() {
+        b"entry" => {
+          self.expect(b"(").await?;
+          self.expect(b"name").await?;
+          let name_offset = self.offset + 8;
+          let name = self.read_str(MAX_NAME_LEN).await?;
+          check_name(&name)?;
+          let dir = self.stack.last_mut().unwrap();
+          if dir.last_name.as_ref().is_some_and(|last| *last >= name) {
+            return Err(invalid_data(format!("NAR directory {:?} isn't sorted", dir.path)));
+          }
+          dir.last_name = Some(name.clone());
+          self.expect(b"node").await?;
+          return self.read_node(dir_path.join(OsStr::from_bytes(&name)), Some(name_offset)).await.map(Some);
+        }
+        b")" => { // end of the directory
+          self.stack.pop();
+          self.end_entry().await?;
+        }
+        t => return Err(invalid_data(format!("unexpected NAR token {:?}", String::from_utf8_lossy(t)))),
+      }
+    }
+  }
+
+  /// Returns a reader over the contents of the regular file last returned by `next_entry`.
+  pub fn contents(&mut self) -> Contents<'_, R> {
+    Contents { decoder: self }
+  }
+
+  async fn read_node(&mut self, path: PathBuf, name_offset: Option<u64>) -> io::Result<Entry> {
+    self.expect(b"(").await?;
+    self.expect(b"type").await?;
+
+    let node = match self.read_str(MAX_NAME_LEN).await?.as_slice() {
+      b"regular" => {
+        let mut executable = false;
+        let mut t = self.read_str(MAX_NAME_LEN).await?;
+        if t == b"executable" {
+          executable = true;
+          self.expect(b"").await?;
+          t = self.read_str(MAX_NAME_LEN).await?;
+        }
+        if t != b"contents" {
+          return Err(invalid_data(format!("unexpected NAR token {:?}", String::from_utf8_lossy(&t))));
+        }
+        let size = self.read_u64().await?;
+        self.state = DecoderState::Contents { remaining: size, size };
+        Node::Regular { executable, size, offset: self.offset }
+      }
+      b"symlink" => {
+        self.expect(b"target").await?;
+        let offset = self.offset + 8;
+        let target = self.read_str(MAX_NAME_LEN).await?;
+        if target.is_empty() || target.contains(&0) {
+          return Err(invalid_data(format!("invalid NAR symlink target {:?}", String::from_utf8_lossy(&target))));
+        }
+        self.expect(b")").await?;
+        self.end_entry().await?;
+        self.state = DecoderState::Node;
+        Node::Symlink { target: PathBuf::from(OsStr::from_bytes(&target)), offset }
+      }
+      b"directory" => {
+        self.stack.push(Directory { path: path.clone(), last_name: None });
+        self.state = DecoderState::Node;
+        Node::Directory
+      }
+      t => return Err(invalid_data(format!("unknown NAR node type {:?}", String::from_utf8_lossy(t)))),
+    };
+
+    Ok(Entry { path, name_offset, node })
+  }
+
+  /// Reads the ")" closing a directory entry, if the node we just finished is in a directory.
+  async fn end_entry(&mut self) -> io::Result<()> {
+    if !self.stack.is_empty() {
+      self.expect(b")").await?;
+    }
+    Ok(())
+  }
+
+  async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+    self.reader.read_exact(buf).await?;
+    self.offset += buf.len() as u64;
+    Ok(())
+  }
+
+  async fn read_u64(&mut self) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    self.read_exact(&mut buf).await?;
+    Ok(u64::from_le_bytes(buf))
+  }
+
+  async fn read_padding(&mut self, len: u64) -> io::Result<()> {
+    let mut buf = [0; 8];
+    let buf = &mut buf[..padding(len)];
+    self.read_exact(buf).await?;
+    if buf.iter().any(|b| *b != 0) {
+      return Err(invalid_data("non-zero padding in NAR"));
+    }
+    Ok(())
+  }
+
+  async fn read_str(&mut self, max_len: u64) -> io::Result<Vec<u8>> {
+    let len = self.read_u64().await?;
+    if len > max_len {
+      return Err(invalid_data(format!("NAR string too long: {len} bytes")));
+    }
+    let mut buf = vec![0; len as usize];
+    self.read_exact(&mut buf).await?;
+    self.read_padding(len).await?;
+    Ok(buf)
+  }
+
+  async fn expect(&mut self, token: &[u8]) -> io::Result<()> {
+    let t = self.read_str(MAX_NAME_LEN).await?;
+    if t != token {
+      return Err(invalid_data(format!("expected NAR token {:?}, got {:?}", String::from_utf8_lossy(token), String::from_utf8_lossy(&t))));
+    }
+    Ok(())
+  }
+
+  async fn skip(&mut self, len: u64) -> io::Result<()> {
+    let skipped = tokio::io::copy(&mut (&mut self.reader).take(len), &mut tokio::io::sink()).await?;
+    self.offset += skipped;
+    if skipped != len {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+  }
+}
+
+/// Reader over the contents of a regular file of a NAR, see `NarDecoder::contents`.
+pub struct Contents<'a, R> {
+  decoder: &'a mut NarDecoder<R>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Contents<'_, R> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let decoder = &mut *self.decoder;
+    let DecoderState::Contents { remaining, .. } = &mut decoder.state else {
+      return Poll::Ready(Ok(()));
+    };
+    if *remaining == 0 || buf.remaining() == 0 {
+      return Poll::Ready(Ok(()));
+    }
+
+    let max = (*remaining).min(buf.remaining() as u64) as usize;
+    let mut limited = ReadBuf::new(buf.initialize_unfilled_to(max));
+    ready!(Pin::new(&mut decoder.reader).poll_read(cx, &mut limited))?;
+    let l = limited.filled().len();
+    if l == 0 {
+      return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+    }
+    buf.advance(l);
+
+    *remaining -= l as u64;
+    decoder.offset += l as u64;
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// Writes a NAR from its entries, given in the order `NarDecoder` returns them.
+///
+/// The contents of each regular file must be written with `write_contents` right after its entry.
+pub struct NarEncoder<W> {
+  writer: W,
+  started: bool,
+  contents: Option<u64>, // size of the regular file whose contents must be written next
+  stack: Vec<Directory>, // directories we're in
+}
+
+impl<W: AsyncWrite + Unpin> NarEncoder<W> {
+  pub fn new(writer: W) -> Self {
+    Self {
+      writer,
+      started: false,
+      contents: None,
+      stack: Vec::new(),
+    }
+  }
+
+  pub async fn write_entry(&mut self, entry: &Entry) -> io::Result<()> {
+    if self.contents.is_some() {
+      return Err(io::Error::other("the contents of the previous NAR entry haven't been written"));
+    }
+
+    if !self.started {
+      if entry.path != Path::new("") {
+        return Err(io::Error::other(format!("the first NAR entry must be the root, got {:?}", entry.path)));
+      }
+      self.started = true;
+      self.write_str(MAGIC).await?;
+    } else {
+      let (Some(parent), Some(name)) = (entry.path.parent(), entry.path.file_name()) else {
+        return Err(io::Error::other(format!("invalid NAR entry path {:?}", entry.path)));
+      };
+
+      // close the directories we're done with
+      while self.stack.last().is_some_and(|dir| dir.path != parent) {
+        self.stack.pop();
+        self.write_str(b")").await?;
+        self.end_entry().await?;
+      }
+      let Some(dir) = self.stack.last_mut() else {
+        return Err(io::Error::other(format!("NAR entry {:?} isn't in an open directory", entry.path)));
+      };
+
+      let name = name.as_bytes();
+      check_name(name)?;
+      if dir.last_name.as_deref().is_some_and(|last| last >= name) {
+        return Err(io::Error::other(format!("NAR entries must be sorted, got {:?} after {:?}", entry.path, dir.last_name.as_deref().map(String::from_utf8_lossy))));
+      }
+      dir.last_name = Some(name.to_vec());
+
+      self.write_str(b"entry").await?;
+      self.write_str(b"(").await?;
+      self.write_str(b"name").await?;
+      self.write_str(name).await?;
+      self.write_str(b"node").await?;
+    }
+
+    self.write_str(b"(").await?;
+    self.write_str(b"type").await?;
+    match &entry.node {
+      Node::Directory => {
+        self.write_str(b"directory").await?;
+        self.stack.push(Directory { path: entry.path.clone(), last_name: None });
+      }
+      Node::Regular { executable, size, .. } => {
+        self.write_str(b"regular").await?;
+        if *executable {
+          self.write_str(b"executable").await?;
+          self.write_str(b"").await?;
+        }
+        self.write_str(b"contents").await?;
+        self.writer.write_all(&size.to_le_bytes()).await?;
+        self.contents = Some(*size);
+      }
+      Node::Symlink { target, .. } => {
+        self.write_str(b"symlink").await?;
+        self.write_str(b"target").await?;
+        self.write_str(target.as_os_str().as_bytes()).await?;
+        self.write_str(b")").await?;
+        self.end_entry().await?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Writes the contents of the regular file last given to `write_entry`, which must be exactly the size announced in the entry.
+  pub async fn write_contents(&mut self, reader: impl AsyncRead + Unpin) -> io::Result<()> {
+    let Some(size) = self.contents.take() else {
+      return Err(io::Error::other("the last NAR entry isn't a regular file"));
+    };
+
+    let mut reader = reader.take(size);
+    let written = tokio::io::copy(&mut reader, &mut self.writer).await?;
+    let mut extra = [0];
+    if written != size || reader.into_inner().read(&mut extra).await? != 0 {
+      return Err(io::Error::other(format!("NAR entry contents size mismatch: expected {size} bytes")));
+    }
+
+    self.writer.write_all(&[0; 8][..padding(size)]).await?;
+    self.write_str(b")").await?;
+    self.end_entry().await
+  }
+
+  /// Closes the remaining directories and returns the underlying writer, flushed.
+  pub async fn finish(mut self) -> io::Result<W> {
+    if !self.started || self.contents.is_some() {
+      return Err(io::Error::other("incomplete NAR"));
+    }
+    while self.stack.pop().is_some() {
+      self.write_str(b")").await?;
+      self.end_entry().await?;
+    }
+    self.writer.flush().await?;
+    Ok(self.writer)
+  }
+
+  /// Writes the ")" closing a directory entry, if the node we just finished is in a directory.
+  async fn end_entry(&mut self) -> io::Result<()> {
+    if !self.stack.is_empty() {
+      self.write_str(b")").await?;
+    }
+    Ok(())
+  }
+
+  async fn write_str(&mut self, s: &[u8]) -> io::Result<()> {
+    self.writer.write_all(&(s.len() as u64).to_le_bytes()).await?;
+    self.writer.write_all(s).await?;
+    self.writer.write_all(&[0; 8][..padding(s.len() as u64)]).await
+  }
+}
+
+/// Lists the entries of a NAR like binary caches do in "<hash>.ls" files, for `nix store ls`.
+pub async fn listing(reader: impl AsyncRead + Unpin) -> io::Result<serde_json::Value> {
+  let mut decoder = NarDecoder::new(reader);
+  let mut root = serde_json::Value::Null;
+  while let Some(entry) = decoder.next_entry().await? {
+    let node = match entry.node {
+      Node::Directory => json!({ "type": "directory", "entries": {} }),
+      Node::Regular { executable, size, offset } => {
+        let mut node = json!({ "type": "regular", "size": size, "narOffset": offset });
+        if executable {
+          node["executable"] = true.into();
+        }
+        node
+      }
+      Node::Symlink { target, .. } => json!({ "type": "symlink", "target": target.to_string_lossy() }),
+    };
+
+    let (Some(parent), Some(name)) = (entry.path.parent(), entry.path.file_name()) else {
+      root = node;
+      continue;
+    };
+    // directories come before their entries
+    let mut dir = &mut root;
+    for component in parent.components() {
+      dir = &mut dir["entries"][component.as_os_str().to_string_lossy().as_ref()];
+    }
+    dir["entries"][name.to_string_lossy().as_ref()] = node;
+  }
+  Ok(json!({ "version": 1, "root": root }))
+}
+
+/// Serializes the file, directory or symlink at `path` as a NAR, like `nix-store --dump`.
+///
+/// The file system is walked by a background task, its errors are returned by the reader.
+pub fn dump_path(path: impl Into<PathBuf>) -> DumpReader {
+  let (writer, reader) = tokio::io::duplex(crate::BUF_SIZE);
+  let task = tokio::spawn(dump(path.into(), writer));
+  DumpReader { reader, task: Some(task) }
+}
+
+async fn dump(root: PathBuf, writer: DuplexStream) -> io::Result<()> {
+  let mut encoder = NarEncoder::new(BufWriter::with_capacity(crate::BUF_SIZE, writer));
+
+  // depth-first, children are pushed in reverse order so they're popped sorted
+  let mut stack = vec![(root, PathBuf::new())];
+  while let Some((fs_path, path)) = stack.pop() {
+    let metadata = tokio::fs::symlink_metadata(&fs_path).await?;
+    let file_type = metadata.file_type();
+
+    if file_type.is_dir() {
+      encoder.write_entry(&Entry { path: path.clone(), name_offset: None, node: Node::Directory }).await?;
+      let mut names = Vec::new();
+      let mut dir = tokio::fs::read_dir(&fs_path).await?;
+      while let Some(child) = dir.next_entry().await? {
+        names.push(child.file_name());
+      }
+      names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+      stack.extend(names.into_iter().rev().map(|name| (fs_path.join(&name), path.join(&name))));
+    } else if file_type.is_file() {
+      let node = Node::Regular {
+        executable: metadata.permissions().mode() & 0o100 != 0, // like Nix, only the owner's bit matters
+        size: metadata.len(),
+        offset: 0,
+      };
+      encoder.write_entry(&Entry { path, name_offset: None, node }).await?;
+      encoder.write_contents(tokio::fs::File::open(&fs_path).await?).await?;
+    } else if file_type.is_symlink() {
+      let node = Node::Symlink { target: tokio::fs::read_link(&fs_path).await?, offset: 0 };
+      encoder.write_entry(&Entry { path, name_offset: None, node }).await?;
+    } else {
+      return Err(io::Error::other(format!("can't serialize {fs_path:?} in a NAR: unsupported file type")));
+    }
+  }
+
+  encoder.finish().await?.shutdown().await
+}
+
+/// Reader returned by `dump_path`.
+pub struct DumpReader {
+  reader: DuplexStream,
+  task: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl AsyncRead for DumpReader {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let filled = buf.filled().len();
+    ready!(Pin::new(&mut self.reader).poll_read(cx, buf))?;
+
+    // at EOF, check the NAR is complete
+    if buf.filled().len() == filled && buf.remaining() > 0 {
+      if let Some(task) = &mut self.task {
+        let res = ready!(Pin::new(task).poll(cx));
+        self.task = None;
+        res.map_err(io::Error::other)??;
+      }
+    }
+    Poll::Ready(Ok(()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn str(s: &[u8]) -> Vec<u8> {
+    let mut v = (s.len() as u64).to_le_bytes().to_vec();
+    v.extend_from_slice(s);
+    v.resize(v.len() + padding(s.len() as u64), 0);
+    v
+  }
+
+  fn nar(tokens: &[&[u8]]) -> Vec<u8> {
+    tokens.iter().flat_map(|t| str(t)).collect()
+  }
+
+  /// A directory with an executable, a symlink and a sub directory holding a regular file.
+  fn sample() -> Vec<u8> {
+    nar(&[
+      b"nix-archive-1", b"(", b"type", b"directory",
+        b"entry", b"(", b"name", b"bin", b"node", b"(", b"type", b"directory",
+          b"entry", b"(", b"name", b"hello", b"node", b"(", b"type", b"regular", b"executable", b"", b"contents", b"#!/bin/sh\necho hello\n", b")", b")",
+        b")", b")",
+        b"entry", b"(", b"name", b"lib", b"node", b"(", b"type", b"symlink", b"target", b"/nix/store/00000000000000000000000000000000-glibc/lib", b")", b")",
+        b"entry", b"(", b"name", b"share", b"node", b"(", b"type", b"directory",
+          b"entry", b"(", b"name", b"README", b"node", b"(", b"type", b"regular", b"contents", b"hi", b")", b")",
+        b")", b")",
+      b")",
+    ])
+  }
+
+  async fn entries(nar: &[u8]) -> io::Result<Vec<(Entry, Vec<u8>)>> {
+    let mut decoder = NarDecoder::new(Cursor::new(nar));
+    let mut entries = Vec::new();
+    while let Some(entry) = decoder.next_entry().await? {
+      let mut contents = Vec::new();
+      decoder.contents().read_to_end(&mut contents).await?;
+      entries.push((entry, contents));
+    }
+    Ok(entries)
+  }
+
+  #[tokio::test]
+  async fn decode() -> io::Result<()> {
+    let nar = sample();
+    let entries = entries(&nar).await?;
+
+    let paths = entries.iter().map(|(e, _)| e.path.to_str().unwrap()).collect::<Vec<_>>();
+    assert_eq!(paths, ["", "bin", "bin/hello", "lib", "share", "share/README"]);
+
+    let (hello, contents) = &entries[2];
+    assert_eq!(contents, b"#!/bin/sh\necho hello\n");
+    let Node::Regular { executable: true, size: 21, offset } = hello.node else { panic!("{hello:?}") };
+    assert_eq!(&nar[offset as usize..offset as usize + 21], b"#!/bin/sh\necho hello\n");
+    let name_offset = hello.name_offset.unwrap() as usize;
+    assert_eq!(&nar[name_offset..name_offset + 5], b"hello");
+
+    let Node::Symlink { target, offset } = &entries[3].0.node else { panic!("{:?}", entries[3]) };
+    assert_eq!(target, Path::new("/nix/store/00000000000000000000000000000000-glibc/lib"));
+    assert_eq!(&nar[*offset as usize..*offset as usize + 10], b"/nix/store");
+
+    assert!(matches!(entries[5].0.node, Node::Regular { executable: false, size: 2, .. }));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn list() -> io::Result<()> {
+    let sample = sample();
+    let offset = |contents: &[u8]| sample.windows(contents.len()).position(|w| w == contents).unwrap();
+    assert_eq!(listing(Cursor::new(&sample)).await?, json!({
+      "version": 1,
+      "root": {
+        "type": "directory",
+        "entries": {
+          "bin": { "type": "directory", "entries": {
+            "hello": { "type": "regular", "size": 21, "executable": true, "narOffset": offset(b"#!/bin/sh") },
+          } },
+          "lib": { "type": "symlink", "target": "/nix/store/00000000000000000000000000000000-glibc/lib" },
+          "share": { "type": "directory", "entries": {
+            "README": { "type": "regular", "size": 2, "narOffset": offset(b"hi\0\0\0\0\0\0") },
+          } },
+        },
+      },
+    }));
+
+    let single_file = nar(&[b"nix-archive-1", b"(", b"type", b"regular", b"contents", b"12345678", b")"]);
+    assert_eq!(listing(Cursor::new(&single_file)).await?, json!({ "version": 1, "root": { "type": "regular", "size": 8, "narOffset": 96 } }));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn skip_contents() -> io::Result<()> {
+    let mut decoder = NarDecoder::new(Cursor::new(sample()));
+    let mut paths = Vec::new();
+    while let Some(entry) = decoder.next_entry().await? {
+      paths.push(entry.path);
+    }
+    assert_eq!(paths.len(), 6);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn empty_read() -> io::Result<()> {
+    let single_file = nar(&[b"nix-archive-1", b"(", b"type", b"regular", b"contents", b"12345678", b")"]);
+    let mut decoder = NarDecoder::new(Cursor::new(single_file));
+    decoder.next_entry().await?;
+    assert_eq!(decoder.contents().read(&mut []).await?, 0);
+    let mut contents = Vec::new();
+    decoder.contents().read_to_end(&mut contents).await?;
+    assert_eq!(contents, b"12345678");
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn roundtrip() -> io::Result<()> {
+    let single_file = nar(&[b"nix-archive-1", b"(", b"type", b"regular", b"contents", b"12345678", b")"]);
+    let single_symlink = nar(&[b"nix-archive-1", b"(", b"type", b"symlink", b"target", b"foo", b")"]);
+    let empty_dir = nar(&[b"nix-archive-1", b"(", b"type", b"directory", b")"]);
+
+    for nar in [sample(), single_file, single_symlink, empty_dir] {
+      let mut decoder = NarDecoder::new(Cursor::new(&nar));
+      let mut encoder = NarEncoder::new(Vec::new());
+      while let Some(entry) = decoder.next_entry().await? {
+        encoder.write_entry(&entry).await?;
+        if let Node::Regular { .. } = entry.node {
+          encoder.write_contents(decoder.contents()).await?;
+        }
+      }
+      assert_eq!(encoder.finish().await?, nar);
+    }
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn invalid() {
+    let bad_magic = nar(&[b"nix-archive-2", b"(", b"type", b"directory", b")"]);
+    let unsorted = nar(&[
+      b"nix-archive-1", b"(", b"type", b"directory",
+        b"entry", b"(", b"name", b"b", b"node", b"(", b"type", b"symlink", b"target", b"x", b")", b")",
+        b"entry", b"(", b"name", b"a", b"node", b"(", b"type", b"symlink", b"target", b"x", b")", b")",
+      b")",
+    ]);
+    let bad_name = nar(&[
+      b"nix-archive-1", b"(", b"type", b"directory",
+        b"entry", b"(", b"name", b"..", b"node", b"(", b"type", b"symlink", b"target", b"x", b")", b")",
+      b")",
+    ]);
+    let mut bad_padding = nar(&[b"nix-archive-1", b"(", b"type", b"regular", b"contents", b"1", b")"]);
+    bad_padding[100] = 1;
+    let truncated = sample()[..200].to_vec();
+
+    for nar in [bad_magic, unsorted, bad_name, bad_padding, truncated] {
+      assert!(entries(&nar).await.is_err());
+    }
+  }
+
+  #[tokio::test]
+  async fn encoder_rejects_unsorted() -> io::Result<()> {
+    let mut encoder = NarEncoder::new(Vec::new());
+    encoder.write_entry(&Entry { path: PathBuf::new(), name_offset: None, node: Node::Directory }).await?;
+    encoder.write_entry(&Entry { path: "b".into(), name_offset: None, node: Node::Directory }).await?;
+    assert!(encoder.write_entry(&Entry { path: "a".into(), name_offset: None, node: Node::Directory }).await.is_err());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn dump() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path().join("root");
+    std::fs::create_dir_all(root.join("share"))?;
+    std::fs::create_dir(root.join("bin"))?;
+    std::fs::write(root.join("share/README"), "hi")?;
+    std::fs::write(root.join("bin/hello"), "#!/bin/sh\necho hello\n")?;
+    std::fs::set_permissions(root.join("bin/hello"), std::fs::Permissions::from_mode(0o555))?;
+    std::os::unix::fs::symlink("/nix/store/00000000000000000000000000000000-glibc/lib", root.join("lib"))?;
+    std::fs::set_permissions(root.join("share/README"), std::fs::Permissions::from_mode(0o600))?;
+
+    let mut dumped = Vec::new();
+    dump_path(&root).read_to_end(&mut dumped).await?;
+    assert_eq!(dumped, sample());
+
+    let mut dumped = Vec::new();
+    dump_path(root.join("lib")).read_to_end(&mut dumped).await?;
+    assert_eq!(dumped, nar(&[b"nix-archive-1", b"(", b"type", b"symlink", b"target", b"/nix/store/00000000000000000000000000000000-glibc/lib", b")"]));
+
+    assert!(dump_path(root.join("missing")).read_to_end(&mut Vec::new()).await.is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,456 @@
+//! NARs downloaded from upstream, kept on disk under a size limit, evicting the least recently used ones first.
+//!
+//! Files are named "<nix32 hash>.nar<compression extension>": NARs by their NarHash, compressed NARs by their FileHash.
+//!
+//! Each file is only downloaded once at a time: concurrent requests read it while it's being written.
+
+use std::{collections::{BTreeMap, HashMap, hash_map::Entry}, future::Future, io, path::{Path, PathBuf}, pin::Pin, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::SystemTime};
+use bytes::Bytes;
+use futures::Stream;
+use color_eyre::eyre;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, sync::watch};
+use tokio_util::io::StreamReader;
+use crate::{compression::{Compression, Reader}, hash::NixHash};
+
+#[derive(Debug, Default)]
+struct Index {
+  entries: HashMap<String, (u64, u64)>, // file name -> (size, last use)
+  lru: BTreeMap<u64, String>, // last use -> file name
+  size: u64,
+  clock: u64,
+}
+
+impl Index {
+  /// Marks an entry as just used, returns its size.
+  fn touch(&mut self, name: &str) -> Option<u64> {
+    let (size, used) = self.entries.get_mut(name)?;
+    self.lru.remove(used);
+    self.clock += 1;
+    *used = self.clock;
+    self.lru.insert(self.clock, name.to_owned());
+    Some(*size)
+  }
+
+  fn insert(&mut self, name: String, size: u64) {
+    self.remove(&name);
+    self.clock += 1;
+    self.entries.insert(name.clone(), (size, self.clock));
+    self.lru.insert(self.clock, name);
+    self.size += size;
+  }
+
+  fn remove(&mut self, name: &str) {
+    if let Some((size, used)) = self.entries.remove(name) {
+      self.lru.remove(&used);
+      self.size -= size;
+    }
+  }
+
+  /// Removes the least recently used entries until the total size is at most `max_size`, returns their names.
+  fn evict(&mut self, max_size: u64) -> Vec<String> {
+    let mut evicted = Vec::new();
+    while self.size > max_size {
+      let Some((_, name)) = self.lru.pop_first() else {
+        break;
+      };
+      let (size, _) = self.entries.remove(&name).expect("entries and lru are in sync");
+      self.size -= size;
+      evicted.push(name);
+    }
+    evicted
+  }
+}
+
+#[derive(Debug, Clone)]
+enum Progress {
+  Opening, // waiting for what to download
+  Missing, // there's nothing to download
+  Written(u64),
+  Done(u64),
+  Failed(String),
+}
+
+#[derive(Debug, Clone)]
+struct Download {
+  path: PathBuf, // of the temporary file being written, once it's opened
+  progress: watch::Receiver<Progress>,
+}
+
+/// An ongoing download, removed from `NarCache::downloads` along with its temporary file when dropped.
+struct Registration {
+  downloads: Arc<Mutex<HashMap<String, Download>>>,
+  name: String,
+  tmp: TempFile,
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    self.downloads.lock().unwrap().remove(&self.name);
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct NarCache {
+  dir: Arc<PathBuf>,
+  max_size: u64, // in bytes
+  index: Arc<Mutex<Index>>,
+  downloads: Arc<Mutex<HashMap<String, Download>>>, // ongoing, by file name
+  count: Arc<AtomicU64>, // to name temporary files
+}
+
+impl NarCache {
+  /// Opens the cache in `dir`, created if needed, which is indexed from the modification times of its files.
+  pub fn open(dir: impl Into<PathBuf>, max_size: u64) -> eyre::Result<Self> {
+    let dir = dir.into();
+    // downloads interrupted by a restart
+    let tmp = dir.join("tmp");
+    if tmp.exists() {
+      std::fs::remove_dir_all(&tmp)?;
+    }
+    std::fs::create_dir_all(&tmp)?;
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+      let entry = entry?;
+      let Ok(name) = entry.file_name().into_string() else {
+        continue;
+      };
+      let metadata = entry.metadata()?;
+      if metadata.is_file() && name.contains(".nar") {
+        files.push((metadata.modified()?, name, metadata.len()));
+      }
+    }
+    files.sort();
+
+    let mut index = Index::default();
+    for (_, name, size) in files {
+      index.insert(name, size);
+    }
+    // the limit may have been lowered
+    for name in index.evict(max_size) {
+      std::fs::remove_file(dir.join(name))?;
+    }
+
+    Ok(Self { dir: Arc::new(dir), max_size, index: Arc::new(Mutex::new(index)), downloads: Default::default(), count: Default::default() })
+  }
+
+  fn file_name(hash: &NixHash, compression: Compression) -> String {
+    format!("{}.nar{}", hash.to_nix32(), compression.extension())
+  }
+
+  pub fn contains(&self, hash: &NixHash, compression: Compression) -> bool {
+    self.index.lock().unwrap().entries.contains_key(&Self::file_name(hash, compression))
+  }
+
+  /// Opens a cached file, returns it along with its size.
+  pub async fn get(&self, hash: &NixHash, compression: Compression) -> eyre::Result<Option<(tokio::fs::File, u64)>> {
+    let name = Self::file_name(hash, compression);
+    let Some(size) = self.index.lock().unwrap().touch(&name) else {
+      return Ok(None);
+    };
+
+    let path = self.dir.join(&name);
+    let file = tokio::task::spawn_blocking(move || {
+      let file = std::fs::File::open(path)?;
+      file.set_modified(SystemTime::now())?; // so that the LRU order survives restarts
+      io::Result::Ok(file)
+    }).await?;
+    match file {
+      Ok(file) => Ok(Some((tokio::fs::File::from_std(file), size))),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        self.index.lock().unwrap().remove(&name);
+        Ok(None)
+      }
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Returns the cached file, or else joins its ongoing download, or else downloads it from what `open` returns.
+  ///
+  /// The download goes on even if the returned reader is dropped. What `open` returns must check the data,
+  /// only files read without errors are cached, and only if they're at most as large as the cache.
+  pub async fn get_or_download(&self, hash: &NixHash, compression: Compression, open: impl Future<Output = eyre::Result<Option<Reader>>>) -> eyre::Result<Option<Reader>> {
+    let name = Self::file_name(hash, compression);
+    // registered before `open` is awaited, so that requests arriving meanwhile wait for it instead of downloading too
+    let (progress, registration) = loop {
+      if let Some((file, _)) = self.get(hash, compression).await? {
+        return Ok(Some(Box::new(file)));
+      }
+      let download = match self.downloads.lock().unwrap().entry(name.clone()) {
+        Entry::Occupied(entry) => entry.get().clone(),
+        Entry::Vacant(entry) => {
+          let path = self.dir.join("tmp").join(format!("{name}.{}", self.count.fetch_add(1, Ordering::Relaxed)));
+          let (progress, receiver) = watch::channel(Progress::Opening);
+          entry.insert(Download { path: path.clone(), progress: receiver });
+          break (progress, Registration { downloads: self.downloads.clone(), name: name.clone(), tmp: TempFile(path) });
+        }
+      };
+
+      let mut progress = download.progress;
+      match progress.wait_for(|p| !matches!(p, Progress::Opening)).await.map(|p| p.clone()) {
+        Err(_) => continue, // abandoned before it started
+        Ok(Progress::Missing) => return Ok(None),
+        Ok(Progress::Failed(e)) => return Err(eyre::eyre!("download failed: {e}")),
+        Ok(_) => {}
+      }
+      match tokio::fs::File::open(&download.path).await {
+        Ok(file) => {
+          tracing::info!(name, "joining an ongoing download");
+          return Ok(Some(tail(file, progress)));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {} // it just finished, so it's cached or it's to be downloaded again
+        Err(e) => return Err(e.into()),
+      }
+    };
+
+    let reader = match open.await {
+      Ok(Some(reader)) => reader,
+      Ok(None) => {
+        progress.send_replace(Progress::Missing);
+        return Ok(None);
+      }
+      Err(e) => {
+        progress.send_replace(Progress::Failed(e.to_string()));
+        return Err(e);
+      }
+    };
+    let file = tokio::fs::File::create(&registration.tmp.0).await?;
+    let tail_file = tokio::fs::File::open(&registration.tmp.0).await?;
+    let receiver = progress.subscribe();
+    progress.send_replace(Progress::Written(0));
+
+    let cache = self.clone();
+    tokio::spawn(async move {
+      match write(reader, file, &progress).await {
+        Ok(size) => {
+          if size <= cache.max_size {
+            if let Err(e) = cache.insert(&registration.tmp.0, name.clone(), size) {
+              tracing::warn!(error = %e, name, "failed to add a file to the NAR cache");
+            }
+          }
+          progress.send_replace(Progress::Done(size));
+        }
+        Err(e) => {
+          tracing::warn!(error = %e, name, "failed to download a file to the NAR cache");
+          progress.send_replace(Progress::Failed(e.to_string()));
+        }
+      }
+      // after it's been added, so that it can always be found
+      drop(registration);
+    });
+
+    Ok(Some(tail(tail_file, receiver)))
+  }
+
+  fn insert(&self, tmp: &Path, name: String, size: u64) -> io::Result<()> {
+    std::fs::rename(tmp, self.dir.join(&name))?;
+    let evicted = {
+      let mut index = self.index.lock().unwrap();
+      index.insert(name, size);
+      index.evict(self.max_size)
+    };
+    for name in evicted {
+      if let Err(e) = std::fs::remove_file(self.dir.join(&name)) {
+        tracing::warn!(error = %e, name, "failed to evict from the NAR cache");
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A file removed when dropped, unless it has been moved.
+#[derive(Debug)]
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_file(&self.0);
+  }
+}
+
+/// Writes `reader` to `file`, telling how much has been written as it goes, returns the size of the file.
+async fn write(mut reader: Reader, mut file: tokio::fs::File, progress: &watch::Sender<Progress>) -> io::Result<u64> {
+  let mut buf = vec![0; crate::BUF_SIZE];
+  let mut written = 0;
+  loop {
+    let n = reader.read(&mut buf).await?;
+    if n == 0 {
+      return Ok(written);
+    }
+    file.write_all(&buf[..n]).await?;
+    file.flush().await?; // so that it can be read
+    written += n as u64;
+    progress.send_replace(Progress::Written(written));
+  }
+}
+
+/// Reads a file while it's being written.
+fn tail(mut file: tokio::fs::File, mut progress: watch::Receiver<Progress>) -> Reader {
+  let stream: Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>> = Box::pin(async_stream::try_stream! {
+    let mut read = 0;
+    loop {
+      let current = progress.borrow_and_update().clone();
+      let (written, done) = match current {
+        Progress::Opening | Progress::Missing => unreachable!("files are tailed once opened"),
+        Progress::Written(written) => (written, false),
+        Progress::Done(written) => (written, true),
+        Progress::Failed(e) => Err(io::Error::other(format!("download failed: {e}")))?,
+      };
+      while read < written {
+        let mut buf = vec![0; (written - read).min(crate::BUF_SIZE as u64) as usize];
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+          Err(io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        }
+        buf.truncate(n);
+        read += n as u64;
+        yield Bytes::from(buf);
+      }
+      if done {
+        break;
+      }
+      progress.changed().await.map_err(|_| io::Error::other("download abandoned"))?;
+    }
+  });
+  Box::new(StreamReader::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sha2::{Digest, Sha256};
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  fn hash(data: &[u8]) -> NixHash {
+    NixHash::sha256(Sha256::digest(data).into())
+  }
+
+  async fn download(cache: &NarCache, data: &'static [u8], nar_hash: NixHash) -> eyre::Result<Vec<u8>> {
+    let open = async move { eyre::Ok(Some(Box::new(crate::VerifyingReader::new(data, nar_hash, data.len() as u64)?) as Reader)) };
+    let mut out = Vec::new();
+    cache.get_or_download(&hash(data), Compression::None, open).await?.unwrap().read_to_end(&mut out).await?;
+    Ok(out)
+  }
+
+  async fn add(cache: &NarCache, data: &'static [u8]) -> eyre::Result<()> {
+    assert_eq!(download(cache, data, hash(data)).await?, data);
+    // the download finishes in the background
+    while !cache.downloads.lock().unwrap().is_empty() {
+      tokio::task::yield_now().await;
+    }
+    Ok(())
+  }
+
+  async fn get(cache: &NarCache, data: &[u8]) -> eyre::Result<Option<Vec<u8>>> {
+    let Some((mut file, size)) = cache.get(&hash(data), Compression::None).await? else {
+      return Ok(None);
+    };
+    let mut out = Vec::new();
+    file.read_to_end(&mut out).await?;
+    assert_eq!(out.len() as u64, size);
+    Ok(Some(out))
+  }
+
+  #[tokio::test]
+  async fn lru() -> eyre::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cache = NarCache::open(dir.path(), 10)?;
+    add(&cache, b"aaaa").await?;
+    add(&cache, b"bbbb").await?;
+    assert_eq!(get(&cache, b"aaaa").await?.as_deref(), Some(&b"aaaa"[..]));
+    add(&cache, b"cccc").await?;
+
+    assert!(get(&cache, b"bbbb").await?.is_none());
+    assert!(get(&cache, b"aaaa").await?.is_some());
+    assert!(get(&cache, b"cccc").await?.is_some());
+
+    // too large to be cached
+    add(&cache, b"ddddddddddd").await?;
+    assert!(get(&cache, b"ddddddddddd").await?.is_none());
+
+    // reads which fail aren't cached
+    assert!(download(&cache, b"eeee", hash(b"eeeE")).await.is_err());
+    while !cache.downloads.lock().unwrap().is_empty() {
+      tokio::task::yield_now().await;
+    }
+    assert!(get(&cache, b"eeee").await?.is_none());
+    assert_eq!(std::fs::read_dir(dir.path().join("tmp"))?.count(), 0);
+
+    // the index is rebuilt, in the same order, when reopened
+    get(&cache, b"aaaa").await?;
+    let cache = NarCache::open(dir.path(), 8)?;
+    add(&cache, b"ffff").await?;
+    assert!(get(&cache, b"cccc").await?.is_none());
+    assert!(get(&cache, b"aaaa").await?.is_some());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn single_flight() -> eyre::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cache = NarCache::open(dir.path(), 1 << 20)?;
+    let data = b"nix-archive-1".repeat(1000);
+    let nar_hash = hash(&data);
+
+    let (mut upstream, reader) = tokio::io::duplex(64);
+    let reader = crate::VerifyingReader::new(reader, nar_hash.clone(), data.len() as u64)?;
+    let first = cache.get_or_download(&nar_hash, Compression::None, async { eyre::Ok(Some(Box::new(reader) as Reader)) }).await?.unwrap();
+    upstream.write_all(&data[..100]).await?;
+    // joins the first download instead of starting another one
+    let second = cache.get_or_download(&nar_hash, Compression::None, async { unreachable!() }).await?.unwrap();
+
+    let read = |mut reader: Reader| tokio::spawn(async move {
+      let mut out = Vec::new();
+      reader.read_to_end(&mut out).await.map(|_| out)
+    });
+    let (first, second) = (read(first), read(second));
+    upstream.write_all(&data[100..]).await?;
+    drop(upstream);
+    assert_eq!(first.await??, data);
+    assert_eq!(second.await??, data);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn single_flight_while_opening() -> eyre::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cache = NarCache::open(dir.path(), 1 << 20)?;
+    let data: &'static [u8] = b"nix-archive-1";
+    let opened = AtomicU64::new(0);
+    // slow to answer, like an upstream
+    let open = |found: bool| {
+      let opened = &opened;
+      async move {
+        opened.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        eyre::Ok(found.then(|| Box::new(data) as Reader))
+      }
+    };
+    let read = |reader: Option<Reader>| async move {
+      let mut out = Vec::new();
+      reader.unwrap().read_to_end(&mut out).await?;
+      eyre::Ok(out)
+    };
+
+    let nar_hash = hash(data);
+    let (first, second) = tokio::join!(
+      cache.get_or_download(&nar_hash, Compression::None, open(true)),
+      cache.get_or_download(&nar_hash, Compression::None, open(true)),
+    );
+    assert_eq!(read(first?).await?, data);
+    assert_eq!(read(second?).await?, data);
+    assert_eq!(opened.swap(0, Ordering::SeqCst), 1);
+
+    // nothing to download
+    let nar_hash = hash(b"missing");
+    let (first, second) = tokio::join!(
+      cache.get_or_download(&nar_hash, Compression::None, open(false)),
+      cache.get_or_download(&nar_hash, Compression::None, open(false)),
+    );
+    assert!(first?.is_none());
+    assert!(second?.is_none());
+    assert_eq!(opened.load(Ordering::SeqCst), 1);
+    assert!(cache.downloads.lock().unwrap().is_empty());
+    Ok(())
+  }
+}
@@ -0,0 +1,200 @@
+use std::{fmt, str::FromStr};
+use color_eyre::eyre;
+use crate::{hash::NixHash, signing::{PublicKey, SecretKey}, store_path::{StoreDir, StorePath}};
+
+/// The metadata of a store path in a binary cache, served as "<hash>.narinfo".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NarInfo {
+  pub store_dir: StoreDir, // of the StorePath field
+  pub store_path: StorePath,
+  pub url: String,
+  pub compression: Option<String>,
+  pub file_hash: Option<NixHash>,
+  pub file_size: Option<u64>,
+  pub nar_hash: NixHash,
+  pub nar_size: u64,
+  pub references: Vec<StorePath>,
+  pub deriver: Option<StorePath>,
+  pub sigs: Vec<String>,
+  pub ca: Option<String>,
+}
+
+impl NarInfo {
+  /// What narinfo signatures sign: "1;<store path>;<NarHash>;<NarSize>;<references>".
+  pub fn fingerprint(&self) -> String {
+    let references = self.references.iter().map(|r| r.to_absolute_path(&self.store_dir)).collect::<Vec<_>>();
+    format!("1;{};{};{};{}", self.store_path.to_absolute_path(&self.store_dir), self.nar_hash, self.nar_size, references.join(","))
+  }
+
+  /// Adds a signature from `key`, replacing the previous one from a key with the same name.
+  pub fn sign(&mut self, key: &SecretKey) {
+    self.sigs.retain(|sig| sig.split_once(':').is_none_or(|(name, _)| name != key.name()));
+    self.sigs.push(key.sign(self.fingerprint().as_bytes()));
+  }
+
+  /// Checks that at least one of the signatures is valid and from a trusted key.
+  pub fn verify(&self, trusted_keys: &[PublicKey]) -> bool {
+    let fingerprint = self.fingerprint();
+    self.sigs.iter().any(|sig| trusted_keys.iter().any(|key| key.verify(fingerprint.as_bytes(), sig)))
+  }
+}
+
+impl FromStr for NarInfo {
+  type Err = eyre::Error;
+
+  fn from_str(s: &str) -> eyre::Result<Self> {
+    let mut store_path = None;
+    let mut url = None;
+    let mut compression = None;
+    let mut file_hash = None;
+    let mut file_size = None;
+    let mut nar_hash = None;
+    let mut nar_size = None;
+    let mut references = None;
+    let mut deriver = None;
+    let mut sigs = Vec::new();
+    let mut ca = None;
+
+    fn set<T>(field: &mut Option<T>, key: &str, value: T) -> eyre::Result<()> {
+      if field.replace(value).is_some() {
+        return Err(eyre::eyre!("duplicate {key} in narinfo"));
+      }
+      Ok(())
+    }
+
+    for line in s.lines() {
+      let (key, value) = line.split_once(": ").or_else(|| line.strip_suffix(':').map(|k| (k, ""))).ok_or_else(|| eyre::eyre!("invalid narinfo line: {line:?}"))?;
+      match key {
+        "StorePath" => {
+          let (dir, base_name) = value.rsplit_once('/').ok_or_else(|| eyre::eyre!("invalid StorePath: {value:?}"))?;
+          set(&mut store_path, key, (StoreDir::new(dir)?, base_name.parse()?))?
+        }
+        "URL" => set(&mut url, key, value.to_owned())?,
+        "Compression" => set(&mut compression, key, value.to_owned())?,
+        "FileHash" => set(&mut file_hash, key, value.parse()?)?,
+        "FileSize" => set(&mut file_size, key, value.parse()?)?,
+        "NarHash" => set(&mut nar_hash, key, value.parse()?)?,
+        "NarSize" => set(&mut nar_size, key, value.parse()?)?,
+        "References" => set(&mut references, key, value.split_whitespace().map(str::parse).collect::<eyre::Result<_>>()?)?,
+        "Deriver" if value == "unknown-deriver" => {}
+        "Deriver" => set(&mut deriver, key, value.parse()?)?,
+        "Sig" => sigs.push(value.to_owned()),
+        "CA" => set(&mut ca, key, value.to_owned())?,
+        _ => {} // like Nix, ignore fields we don't know about
+      }
+    }
+
+    let missing = |key| eyre::eyre!("no {key} in narinfo");
+    let (store_dir, store_path) = store_path.ok_or_else(|| missing("StorePath"))?;
+    Ok(Self {
+      store_dir,
+      store_path,
+      url: url.ok_or_else(|| missing("URL"))?,
+      compression,
+      file_hash,
+      file_size,
+      nar_hash: nar_hash.ok_or_else(|| missing("NarHash"))?,
+      nar_size: nar_size.ok_or_else(|| missing("NarSize"))?,
+      references: references.unwrap_or_default(),
+      deriver,
+      sigs,
+      ca,
+    })
+  }
+}
+
+/// Serializes the fields in the same order as Nix.
+impl fmt::Display for NarInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "StorePath: {}", self.store_path.to_absolute_path(&self.store_dir))?;
+    writeln!(f, "URL: {}", self.url)?;
+    if let Some(compression) = &self.compression {
+      writeln!(f, "Compression: {compression}")?;
+    }
+    if let Some(file_hash) = &self.file_hash {
+      writeln!(f, "FileHash: {file_hash}")?;
+    }
+    if let Some(file_size) = self.file_size {
+      writeln!(f, "FileSize: {file_size}")?;
+    }
+    writeln!(f, "NarHash: {}", self.nar_hash)?;
+    writeln!(f, "NarSize: {}", self.nar_size)?;
+    writeln!(f, "References: {}", self.references.iter().map(StorePath::to_string).collect::<Vec<_>>().join(" "))?;
+    if let Some(deriver) = &self.deriver {
+      writeln!(f, "Deriver: {deriver}")?;
+    }
+    for sig in &self.sigs {
+      writeln!(f, "Sig: {sig}")?;
+    }
+    if let Some(ca) = &self.ca {
+      writeln!(f, "CA: {ca}")?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const HELLO: &str = "StorePath: /nix/store/3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1
+URL: nar/1ivslgrn7bi7cymqlw0q1gql6ds6xgxkvcfjlb2z31ma7i8s6s3g.nar.xz
+Compression: xz
+FileHash: sha256:1ivslgrn7bi7cymqlw0q1gql6ds6xgxkvcfjlb2z31ma7i8s6s3g
+FileSize: 50108
+NarHash: sha256:0hb5svmr2jxyqsnrc5n7sqgr3pp4p8cvzzc23j2pn0ar0h4zvx0s
+NarSize: 226560
+References: 3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1 qn3ggz5sf3hkjs2c797xf7nan3amdxmp-glibc-2.38-27
+Deriver: 1dwcgvkmnfkpa3ks2d8zdjmxxfkgdjqq-hello-2.12.1.drv
+Sig: cache.nixos.org-1:8ijECciSFzWHwwGVOIVYdp2fOIOJAfmzGHPQVwpktfTQJF6kMPPDre7UtFw3o+VqenC5P8RikKOAAfN7CvPEAg==
+";
+
+  #[test]
+  fn roundtrip() -> eyre::Result<()> {
+    let narinfo: NarInfo = HELLO.parse()?;
+    assert_eq!(narinfo.store_path.hash(), b"3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp");
+    assert_eq!(narinfo.nar_size, 226560);
+    assert_eq!(narinfo.references.len(), 2);
+    assert_eq!(narinfo.to_string(), HELLO);
+
+    // fields are always written in the same order
+    let shuffled = HELLO.lines().rev().collect::<Vec<_>>().join("\n");
+    assert_eq!(shuffled.parse::<NarInfo>()?.to_string(), HELLO);
+    Ok(())
+  }
+
+  #[test]
+  fn sign() -> eyre::Result<()> {
+    let mut narinfo: NarInfo = HELLO.parse()?;
+    assert_eq!(narinfo.fingerprint(), "1;/nix/store/3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1;sha256:0hb5svmr2jxyqsnrc5n7sqgr3pp4p8cvzzc23j2pn0ar0h4zvx0s;226560;/nix/store/3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1,/nix/store/qn3ggz5sf3hkjs2c797xf7nan3amdxmp-glibc-2.38-27");
+
+    // seed 0, 1, .., 31
+    let key: SecretKey = "test-1:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8DoQe/884Qvh1w3RjnS8CZZ+TWMJulDV8d3IZkElUxuA==".parse()?;
+    narinfo.sign(&key);
+    narinfo.sign(&key); // replaces the previous signature
+    assert_eq!(narinfo.sigs.len(), 2);
+    assert_eq!(narinfo.sigs[1], "test-1:IORqXKDqkfPcErE7Fkl4px1J4soDHEc9KTAQ4onacIWYYx5g2sS/uYuCuLqVSmXJs1ocUIwDrg9U3KdnHNovBg==");
+
+    let trusted = [key.public_key()];
+    assert!(narinfo.verify(&trusted));
+    assert!(!narinfo.verify(&["cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".parse()?]));
+    narinfo.nar_size += 1;
+    assert!(!narinfo.verify(&trusted));
+    Ok(())
+  }
+
+  #[test]
+  fn invalid() {
+    for (from, to) in [
+      ("NarSize: 226560\n", ""), // missing field
+      ("NarSize: 226560", "NarSize: -1"),
+      ("NarHash: sha256:", "NarHash: sha256:x"),
+      ("URL: nar", "URL: a\nURL: nar"), // duplicate field
+      ("/nix/store/3sg0", "3sg0"),
+      ("qn3ggz5sf3hkjs2c797xf7nan3amdxmp-glibc", "qn3ggz5sf3hkjs2c797xf7nan3amdxmp/glibc"),
+      ("Compression: xz", "Compression xz"),
+    ] {
+      assert!(HELLO.replace(from, to).parse::<NarInfo>().is_err(), "{from:?} -> {to:?}");
+    }
+  }
+}
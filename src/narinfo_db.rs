@@ -0,0 +1,209 @@
+//! On-disk database of the narinfos fetched from upstream, so that NARs can still be served after a restart.
+
+use std::{path::Path, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use color_eyre::eyre;
+use redb::{Database, ReadableTableMetadata, TableDefinition};
+
+const NARINFOS: TableDefinition<&str, &str> = TableDefinition::new("narinfos"); // store path hash -> narinfo, as received from upstream
+const UPSTREAMS: TableDefinition<&str, &str> = TableDefinition::new("upstreams"); // store path hash -> URL of the upstream its narinfo comes from
+const NAR_URLS: TableDefinition<&str, &str> = TableDefinition::new("nar_urls"); // URL of a NAR we serve -> store path hash
+const FAILED_ALIKES: TableDefinition<(&str, &str), ()> = TableDefinition::new("failed_alikes"); // (URL of a NAR we serve, installed store path it couldn't be rebuilt from)
+const MISSES: TableDefinition<&str, &str> = TableDefinition::new("misses"); // store path hash -> when no upstream had it, in seconds since the epoch
+
+#[derive(Debug, Clone)]
+pub struct NarInfoDb {
+  db: Arc<Database>,
+}
+
+impl NarInfoDb {
+  pub fn open(path: impl AsRef<Path>) -> eyre::Result<Self> {
+    let db = Database::create(path)?;
+
+    let tx = db.begin_write()?;
+    tx.open_table(NARINFOS)?;
+    tx.open_table(UPSTREAMS)?;
+    tx.open_table(NAR_URLS)?;
+    tx.open_table(FAILED_ALIKES)?;
+    tx.open_table(MISSES)?;
+    tx.commit()?;
+
+    Ok(Self { db: Arc::new(db) })
+  }
+
+  pub async fn narinfo(&self, hash: &str) -> eyre::Result<Option<String>> {
+    self.get(NARINFOS, hash).await
+  }
+
+  /// The URL of the upstream the narinfo comes from.
+  pub async fn upstream(&self, hash: &str) -> eyre::Result<Option<String>> {
+    self.get(UPSTREAMS, hash).await
+  }
+
+  pub async fn insert_narinfo(&self, hash: &str, narinfo: &str, upstream: &str) -> eyre::Result<()> {
+    let db = self.db.clone();
+    let (hash, narinfo, upstream) = (hash.to_owned(), narinfo.to_owned(), upstream.to_owned());
+    tokio::task::spawn_blocking(move || {
+      // both or neither
+      let tx = db.begin_write()?;
+      tx.open_table(NARINFOS)?.insert(hash.as_str(), narinfo.as_str())?;
+      tx.open_table(UPSTREAMS)?.insert(hash.as_str(), upstream.as_str())?;
+      tx.commit()?;
+      Ok(())
+    }).await?
+  }
+
+  /// The hash of the store path whose NAR we serve at `url`.
+  pub async fn nar_url(&self, url: &str) -> eyre::Result<Option<String>> {
+    self.get(NAR_URLS, url).await
+  }
+
+  pub async fn insert_nar_url(&self, url: &str, hash: &str) -> eyre::Result<()> {
+    self.insert(NAR_URLS, url, hash).await
+  }
+
+  /// The base names of the installed store paths the NAR we serve at `url` couldn't be rebuilt from.
+  pub async fn failed_alikes(&self, url: &str) -> eyre::Result<Vec<String>> {
+    let db = self.db.clone();
+    let url = url.to_owned();
+    tokio::task::spawn_blocking(move || {
+      let tx = db.begin_read()?;
+      let table = tx.open_table(FAILED_ALIKES)?;
+      let mut store_paths = Vec::new();
+      for entry in table.range((url.as_str(), "")..)? {
+        let (key, _) = entry?;
+        let (entry_url, store_path) = key.value();
+        if entry_url != url {
+          break;
+        }
+        store_paths.push(store_path.to_owned());
+      }
+      Ok(store_paths)
+    }).await?
+  }
+
+  pub async fn insert_failed_alike(&self, url: &str, store_path: &str) -> eyre::Result<()> {
+    let db = self.db.clone();
+    let (url, store_path) = (url.to_owned(), store_path.to_owned());
+    tokio::task::spawn_blocking(move || {
+      let tx = db.begin_write()?;
+      tx.open_table(FAILED_ALIKES)?.insert((url.as_str(), store_path.as_str()), ())?;
+      tx.commit()?;
+      Ok(())
+    }).await?
+  }
+
+  /// When no upstream had the narinfo, the last time it was looked for.
+  pub async fn miss(&self, hash: &str) -> eyre::Result<Option<SystemTime>> {
+    let Some(secs) = self.get(MISSES, hash).await? else {
+      return Ok(None);
+    };
+    Ok(Some(UNIX_EPOCH + Duration::from_secs(secs.parse()?)))
+  }
+
+  pub async fn insert_miss(&self, hash: &str) -> eyre::Result<()> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    self.insert(MISSES, hash, &secs.to_string()).await
+  }
+
+  /// Returns whether there was a miss to remove.
+  pub async fn remove_miss(&self, hash: &str) -> eyre::Result<bool> {
+    self.remove(MISSES, hash).await
+  }
+
+  /// Returns how many misses were removed.
+  pub async fn clear_misses(&self) -> eyre::Result<u64> {
+    let db = self.db.clone();
+    tokio::task::spawn_blocking(move || {
+      let tx = db.begin_write()?;
+      let count = tx.open_table(MISSES)?.len()?;
+      tx.delete_table(MISSES)?;
+      tx.open_table(MISSES)?;
+      tx.commit()?;
+      Ok(count)
+    }).await?
+  }
+
+  // redb is blocking, writes even wait for fsync
+
+  async fn get(&self, table: TableDefinition<'static, &'static str, &'static str>, key: &str) -> eyre::Result<Option<String>> {
+    let db = self.db.clone();
+    let key = key.to_owned();
+    tokio::task::spawn_blocking(move || {
+      let tx = db.begin_read()?;
+      let table = tx.open_table(table)?;
+      Ok(table.get(key.as_str())?.map(|v| v.value().to_owned()))
+    }).await?
+  }
+
+  async fn insert(&self, table: TableDefinition<'static, &'static str, &'static str>, key: &str, value: &str) -> eyre::Result<()> {
+    let db = self.db.clone();
+    let (key, value) = (key.to_owned(), value.to_owned());
+    tokio::task::spawn_blocking(move || {
+      let tx = db.begin_write()?;
+      tx.open_table(table)?.insert(key.as_str(), value.as_str())?;
+      tx.commit()?;
+      Ok(())
+    }).await?
+  }
+
+  async fn remove(&self, table: TableDefinition<'static, &'static str, &'static str>, key: &str) -> eyre::Result<bool> {
+    let db = self.db.clone();
+    let key = key.to_owned();
+    tokio::task::spawn_blocking(move || {
+      let tx = db.begin_write()?;
+      let removed = tx.open_table(table)?.remove(key.as_str())?.is_some();
+      tx.commit()?;
+      Ok(removed)
+    }).await?
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn persists() -> eyre::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("db.redb");
+
+    let db = NarInfoDb::open(&path)?;
+    assert_eq!(db.narinfo("abc").await?, None);
+    db.insert_narinfo("abc", "StorePath: /nix/store/abc-hello\n", "https://cache.nixos.org").await?;
+    db.insert_nar_url("nar/def.nar", "abc").await?;
+    db.insert_failed_alike("nar/def.nar", "ghi-hello").await?;
+    db.insert_failed_alike("nar/def.nar", "jkl-hello").await?;
+    db.insert_failed_alike("nar/def.nara", "mno-hello").await?;
+    drop(db);
+
+    let db = NarInfoDb::open(&path)?;
+    assert_eq!(db.narinfo("abc").await?.as_deref(), Some("StorePath: /nix/store/abc-hello\n"));
+    assert_eq!(db.upstream("abc").await?.as_deref(), Some("https://cache.nixos.org"));
+    assert_eq!(db.nar_url("nar/def.nar").await?.as_deref(), Some("abc"));
+    assert_eq!(db.failed_alikes("nar/def.nar").await?, ["ghi-hello", "jkl-hello"]);
+    assert!(db.failed_alikes("nar/abc.nar").await?.is_empty());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn misses() -> eyre::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = NarInfoDb::open(dir.path().join("db.redb"))?;
+    assert_eq!(db.miss("abc").await?, None);
+
+    db.insert_miss("abc").await?;
+    db.insert_miss("def").await?;
+    assert!(db.miss("abc").await?.unwrap().elapsed()? < Duration::from_secs(60));
+
+    assert!(db.remove_miss("abc").await?);
+    assert!(!db.remove_miss("abc").await?);
+    assert_eq!(db.miss("abc").await?, None);
+
+    db.insert_miss("ghi").await?;
+    assert_eq!(db.clear_misses().await?, 2);
+    assert_eq!(db.miss("def").await?, None);
+    db.insert_miss("def").await?;
+    assert!(db.miss("def").await?.is_some());
+    Ok(())
+  }
+}
@@ -0,0 +1,78 @@
+//! HTTP "Range" requests, only single byte ranges are supported.
+
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ByteRange {
+  Full, // no range, or one that must be ignored
+  Partial(Range<u64>),
+  Unsatisfiable,
+}
+
+impl ByteRange {
+  /// Parses a "Range" header for a body of `size` bytes.
+  ///
+  /// Like the RFC allows, invalid headers and multiple ranges are ignored.
+  pub fn parse(header: Option<&str>, size: u64) -> Self {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+      return Self::Full;
+    };
+    if spec.contains(',') {
+      return Self::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+      return Self::Full;
+    };
+
+    let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+      // the last n bytes
+      _ if first.is_empty() => match last.parse::<u64>() {
+        Ok(0) => return Self::Unsatisfiable,
+        Ok(n) => size.saturating_sub(n)..size,
+        Err(_) => return Self::Full,
+      },
+      (Ok(first), _) if last.is_empty() => first..size,
+      (Ok(first), Ok(last)) if first <= last => first..size.min(last.saturating_add(1)),
+      _ => return Self::Full,
+    };
+    if range.start >= size {
+      return Self::Unsatisfiable;
+    }
+    Self::Partial(range)
+  }
+
+  /// Where the body starts.
+  pub fn start(&self) -> u64 {
+    match self {
+      Self::Partial(range) => range.start,
+      _ => 0,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() {
+    for (header, expected) in [
+      (None, ByteRange::Full),
+      (Some("bytes=0-99"), ByteRange::Partial(0..100)),
+      (Some("bytes=100-"), ByteRange::Partial(100..1000)),
+      (Some("bytes=900-2000"), ByteRange::Partial(900..1000)),
+      (Some("bytes=0-18446744073709551615"), ByteRange::Partial(0..1000)),
+      (Some("bytes=-10"), ByteRange::Partial(990..1000)),
+      (Some("bytes=-2000"), ByteRange::Partial(0..1000)),
+      (Some("bytes=1000-"), ByteRange::Unsatisfiable),
+      (Some("bytes=-0"), ByteRange::Unsatisfiable),
+      (Some("bytes=10-5"), ByteRange::Full),
+      (Some("bytes=0-1,5-6"), ByteRange::Full),
+      (Some("bytes=a-"), ByteRange::Full),
+      (Some("lines=0-1"), ByteRange::Full),
+    ] {
+      assert_eq!(ByteRange::parse(header, 1000), expected, "{header:?}");
+    }
+    assert_eq!(ByteRange::parse(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+  }
+}
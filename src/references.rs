@@ -0,0 +1,146 @@
+use std::{collections::{BTreeSet, HashSet}, io, pin::Pin, task::{Context, Poll}};
+use color_eyre::eyre;
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::{store_path::StoreDir, store_path_automaton::StorePathAutomaton};
+
+const HASH_LEN: usize = 32;
+
+/// Store path hashes referenced by a NAR, see `AsyncReferenceScanner`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct References {
+  pub found: BTreeSet<[u8; HASH_LEN]>, // candidates found in the NAR
+  pub unknown: BTreeSet<[u8; HASH_LEN]>, // hashes of store paths found in the NAR which aren't candidates
+}
+
+/// Finds which of the candidate store path hashes a NAR references, like Nix's `scanForReferences`.
+///
+/// Like Nix, candidates are searched as bare hashes anywhere in the NAR, not only following the store dir,
+/// so that references from e.g. UTF-16 strings or truncated paths are found too.
+/// Complete store paths whose hash isn't a candidate are reported as unknown.
+pub struct AsyncReferenceScanner {
+  candidates: HashSet<[u8; HASH_LEN]>,
+  automaton: StorePathAutomaton,
+  pending: Vec<u8>, // the last bytes written, which can still be part of a hash
+  run: usize, // number of consecutive base32 chars at the end of `pending`
+  references: References,
+}
+
+fn is_nix32(byte: u8) -> bool {
+  matches!(byte, b'0'..=b'9' | b'a'..=b'd' | b'f'..=b'n' | b'p'..=b's' | b'v'..=b'z')
+}
+
+impl AsyncReferenceScanner {
+  pub fn new(store_dir: &StoreDir, candidates: impl IntoIterator<Item = [u8; HASH_LEN]>) -> Self {
+    Self {
+      candidates: candidates.into_iter().collect(),
+      automaton: StorePathAutomaton::with_store_dir(store_dir),
+      pending: Vec::new(),
+      run: 0,
+      references: References::default(),
+    }
+  }
+
+  pub fn references(&self) -> &References {
+    &self.references
+  }
+
+  pub fn into_references(self) -> References {
+    self.references
+  }
+
+  fn update(&mut self, buf: &[u8]) {
+    let start = self.pending.len();
+    self.pending.extend_from_slice(buf);
+
+    for j in start..self.pending.len() {
+      if !is_nix32(self.pending[j]) {
+        self.run = 0;
+        continue;
+      }
+      self.run += 1;
+      if self.run >= HASH_LEN {
+        let hash: [u8; HASH_LEN] = self.pending[j + 1 - HASH_LEN..=j].try_into().unwrap();
+        if self.candidates.contains(&hash) {
+          self.references.found.insert(hash);
+        }
+      }
+    }
+
+    let mut i = start;
+    while let Some(m) = self.automaton.find(&self.pending[i..]) {
+      // the automaton stops on the "-" following the hash
+      let hash: [u8; HASH_LEN] = self.pending[i + m - HASH_LEN..i + m].try_into().unwrap();
+      if !self.candidates.contains(&hash) {
+        self.references.unknown.insert(hash);
+      }
+      i += m + 1;
+    }
+
+    // only the last 32 bytes can still be part of a hash
+    let done = self.pending.len().saturating_sub(HASH_LEN);
+    self.pending.drain(..done);
+  }
+}
+
+impl AsyncWrite for AsyncReferenceScanner {
+  fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+    self.update(buf);
+    Poll::Ready(Ok(buf.len()))
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// Scans a NAR for references to `candidates`, see `AsyncReferenceScanner`.
+pub async fn scan_references(store_dir: &StoreDir, mut reader: impl AsyncRead + Unpin, candidates: impl IntoIterator<Item = [u8; HASH_LEN]>) -> eyre::Result<References> {
+  let mut scanner = AsyncReferenceScanner::new(store_dir, candidates);
+  tokio::io::copy(&mut reader, &mut scanner).await?;
+  Ok(scanner.into_references())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::AsyncWriteExt;
+
+  const A: [u8; 32] = *b"abcdfghijklmnpqrsvwxyz0000000000";
+  const B: [u8; 32] = *b"11111111111111111111111111111111";
+  const C: [u8; 32] = *b"22222222222222222222222222222222";
+  const D: [u8; 32] = *b"33333333333333333333333333333333";
+
+  #[tokio::test]
+  async fn scan() -> eyre::Result<()> {
+    let nar = [
+      b"ELF /nix/store/abcdfghijklmnpqrsvwxyz0000000000-glibc/lib\0".as_slice(),
+      b"bare 11111111111111111111111111111111 hash, ",
+      b"/nix/store/33333333333333333333333333333333-unknown ",
+      b"x22222222222222222222222222222222", // no word boundary needed
+    ].concat();
+
+    // every possible chunking must give the same result
+    for chunk in 1..nar.len() {
+      let mut scanner = AsyncReferenceScanner::new(&StoreDir::default(), [A, B, C, *b"44444444444444444444444444444444"]);
+      for c in nar.chunks(chunk) {
+        scanner.write_all(c).await?;
+      }
+      let references = scanner.into_references();
+      assert_eq!(references.found, BTreeSet::from([A, B, C]), "chunk size {chunk}");
+      assert_eq!(references.unknown, BTreeSet::from([D]), "chunk size {chunk}");
+    }
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn no_false_positive() -> eyre::Result<()> {
+    // the candidate straddles a character which isn't part of the base32 alphabet
+    let references = scan_references(&StoreDir::default(), &b"abcdfghijklmnpqrsvwxyz000000000e0 /nix/store/abc-"[..], [A]).await?;
+    assert_eq!(references, References::default());
+    Ok(())
+  }
+}
@@ -0,0 +1,121 @@
+//! ed25519 keys in Nix's "<name>:<base64>" format, as created by `nix key generate-secret`.
+
+use std::{fmt, str::FromStr};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use color_eyre::eyre;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+fn split_key(s: &str) -> eyre::Result<(&str, Vec<u8>)> {
+  let (name, key) = s.trim().split_once(':').ok_or_else(|| eyre::eyre!("key without name"))?;
+  if name.is_empty() {
+    return Err(eyre::eyre!("key without name"));
+  }
+  Ok((name, BASE64.decode(key).map_err(|e| eyre::eyre!("invalid key {name:?}: {e}"))?))
+}
+
+pub struct SecretKey {
+  name: String,
+  key: SigningKey,
+}
+
+impl SecretKey {
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Returns "<key name>:<base64 signature>".
+  pub fn sign(&self, data: &[u8]) -> String {
+    format!("{}:{}", self.name, BASE64.encode(self.key.sign(data).to_bytes()))
+  }
+
+  pub fn public_key(&self) -> PublicKey {
+    PublicKey { name: self.name.clone(), key: self.key.verifying_key() }
+  }
+}
+
+impl FromStr for SecretKey {
+  type Err = eyre::Error;
+
+  fn from_str(s: &str) -> eyre::Result<Self> {
+    let (name, key) = split_key(s)?;
+    // Nix stores the seed followed by the public key
+    let key = key.as_slice().try_into().map_err(|_| eyre::eyre!("secret key {name:?} must be 64 bytes long"))?;
+    let key = SigningKey::from_keypair_bytes(key).map_err(|_| eyre::eyre!("secret key {name:?} doesn't match its public key"))?;
+    Ok(Self { name: name.to_owned(), key })
+  }
+}
+
+impl fmt::Debug for SecretKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("SecretKey").field("name", &self.name).finish_non_exhaustive()
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+  name: String,
+  key: VerifyingKey,
+}
+
+impl PublicKey {
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Checks a "<key name>:<base64 signature>" of `data`, signatures from other keys are never valid.
+  pub fn verify(&self, data: &[u8], sig: &str) -> bool {
+    let Some((name, sig)) = sig.split_once(':') else {
+      return false;
+    };
+    let Ok(Ok(sig)) = BASE64.decode(sig).map(|s| Signature::from_slice(&s)) else {
+      return false;
+    };
+    name == self.name && self.key.verify(data, &sig).is_ok()
+  }
+}
+
+impl FromStr for PublicKey {
+  type Err = eyre::Error;
+
+  fn from_str(s: &str) -> eyre::Result<Self> {
+    let (name, key) = split_key(s)?;
+    let key = key.as_slice().try_into().map_err(|_| eyre::eyre!("public key {name:?} must be 32 bytes long"))?;
+    let key = VerifyingKey::from_bytes(key).map_err(|_| eyre::eyre!("invalid public key {name:?}"))?;
+    Ok(Self { name: name.to_owned(), key })
+  }
+}
+
+impl fmt::Display for PublicKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.name, BASE64.encode(self.key.as_bytes()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keys() -> eyre::Result<()> {
+    let key = SigningKey::from_bytes(&[1; 32]);
+    let secret: SecretKey = format!("test-1:{}", BASE64.encode(key.to_keypair_bytes())).parse()?;
+    assert_eq!(secret.name(), "test-1");
+
+    let public = secret.public_key();
+    assert_eq!(public.to_string().parse::<PublicKey>()?, public);
+
+    let sig = secret.sign(b"hello");
+    assert!(public.verify(b"hello", &sig));
+    assert!(!public.verify(b"hellO", &sig));
+    assert!(!public.verify(b"hello", &sig.replace("test-1:", "test-2:")));
+    assert!(!public.verify(b"hello", "test-1:AAAA"));
+
+    // the public key half doesn't match the seed
+    let mut bytes = key.to_keypair_bytes();
+    bytes[63] ^= 1;
+    assert!(format!("test-1:{}", BASE64.encode(bytes)).parse::<SecretKey>().is_err());
+    assert!("test-1:AAAA".parse::<SecretKey>().is_err());
+    assert!(":AAAA".parse::<PublicKey>().is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,166 @@
+use std::{fmt, str::FromStr};
+use color_eyre::eyre;
+
+/// The directory store paths live in, "/nix/store" by default.
+///
+/// Always absolute and without trailing "/".
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StoreDir(String);
+
+impl StoreDir {
+  pub fn new(path: impl Into<String>) -> eyre::Result<Self> {
+    let mut path = path.into();
+    while path.len() > 1 && path.ends_with('/') {
+      path.pop();
+    }
+
+    if !path.starts_with('/') {
+      return Err(eyre::eyre!("store dir must be an absolute path: {path:?}"));
+    }
+    if path == "/" {
+      return Err(eyre::eyre!("store dir can't be the root directory"));
+    }
+    if path.len() > 200 {
+      return Err(eyre::eyre!("store dir too long: {path:?}"));
+    }
+    if path.chars().any(|c| c.is_control()) {
+      return Err(eyre::eyre!("store dir can't contain control characters: {path:?}"));
+    }
+
+    Ok(Self(path))
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+}
+
+impl Default for StoreDir {
+  fn default() -> Self {
+    Self("/nix/store".to_owned())
+  }
+}
+
+impl FromStr for StoreDir {
+  type Err = eyre::Error;
+
+  fn from_str(s: &str) -> eyre::Result<Self> {
+    Self::new(s)
+  }
+}
+
+impl fmt::Display for StoreDir {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+/// A store path without its store dir, e.g. "<hash>-hello-2.12.1".
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StorePath {
+  hash: [u8; 32], // nix base32 chars, as they appear in NARs
+  name: String,
+}
+
+impl StorePath {
+  pub fn new(hash: [u8; 32], name: impl Into<String>) -> eyre::Result<Self> {
+    let name = name.into();
+    if !hash.iter().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'd' | b'f'..=b'n' | b'p'..=b's' | b'v'..=b'z')) {
+      return Err(eyre::eyre!("invalid store path hash: {:?}", String::from_utf8_lossy(&hash)));
+    }
+    // same rules as Nix's checkName
+    if name.is_empty() || name.len() > 211 {
+      return Err(eyre::eyre!("store path name must be 1 to 211 chars long: {name:?}"));
+    }
+    if name.starts_with('.') {
+      return Err(eyre::eyre!("store path name can't start with a dot: {name:?}"));
+    }
+    if !name.bytes().all(|c| c.is_ascii_alphanumeric() || b"+-._?=".contains(&c)) {
+      return Err(eyre::eyre!("invalid character in store path name: {name:?}"));
+    }
+    Ok(Self { hash, name })
+  }
+
+  /// Parses the hash of a store path alone, e.g. from "<hash>.narinfo".
+  pub fn parse_hash(hash: &str) -> eyre::Result<[u8; 32]> {
+    let bytes: [u8; 32] = hash.as_bytes().try_into().map_err(|_| eyre::eyre!("invalid store path hash: {hash:?}"))?;
+    Ok(*Self::new(bytes, "x")?.hash())
+  }
+
+  /// Parses "<hash>-<name>".
+  pub fn from_base_name(base_name: &str) -> eyre::Result<Self> {
+    let (hash, name) = base_name.split_at_checked(32).filter(|(_, name)| name.starts_with('-')).ok_or_else(|| eyre::eyre!("invalid store path: {base_name:?}"))?;
+    Self::new(hash.as_bytes().try_into()?, &name[1..])
+  }
+
+  /// Parses "<store dir>/<hash>-<name>".
+  pub fn from_absolute_path(store_dir: &StoreDir, path: &str) -> eyre::Result<Self> {
+    let base_name = path.strip_prefix(store_dir.as_str()).and_then(|p| p.strip_prefix('/')).ok_or_else(|| eyre::eyre!("{path:?} isn't in the store dir {store_dir}"))?;
+    Self::from_base_name(base_name)
+  }
+
+  pub fn hash(&self) -> &[u8; 32] {
+    &self.hash
+  }
+
+  pub fn hash_str(&self) -> &str {
+    std::str::from_utf8(&self.hash).unwrap() // checked in new
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn to_absolute_path(&self, store_dir: &StoreDir) -> String {
+    format!("{store_dir}/{self}")
+  }
+}
+
+impl FromStr for StorePath {
+  type Err = eyre::Error;
+
+  fn from_str(s: &str) -> eyre::Result<Self> {
+    Self::from_base_name(s)
+  }
+}
+
+impl fmt::Display for StorePath {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}-{}", self.hash_str(), self.name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn store_path() -> eyre::Result<()> {
+    let store_dir = StoreDir::default();
+    let path = StorePath::from_absolute_path(&store_dir, "/nix/store/3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1")?;
+    assert_eq!(path.hash(), b"3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp");
+    assert_eq!(path.name(), "hello-2.12.1");
+    assert_eq!(path.to_string(), "3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1");
+    assert_eq!(path.to_absolute_path(&store_dir), "/nix/store/3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1");
+    assert_eq!("3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1".parse::<StorePath>()?, path);
+
+    for invalid in [
+      "3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp",
+      "3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-",
+      "3sg0djrhdnx6b5dxh8n3ax4w3l6lvhle-hello", // "e" isn't nix base32
+      "3sg0djrhdnx6b5dxh8n3ax4w3l6lvhl-hello",
+      "3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-.hello",
+      "3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello/bin",
+      "3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-h\u{e9}llo",
+    ] {
+      assert!(invalid.parse::<StorePath>().is_err(), "{invalid:?}");
+    }
+    assert!(StorePath::from_absolute_path(&store_dir, "/gnu/store/3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello").is_err());
+
+    assert_eq!(&StorePath::parse_hash("3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp")?, path.hash());
+    for invalid in ["", "3sg0djrhdnx6b5dxh8n3ax4w3l6lvhl", "3sg0djrhdnx6b5dxh8n3ax4w3l6lvhle", "../../3sg0djrhdnx6b5dxh8n3ax4w3l6"] {
+      assert!(StorePath::parse_hash(invalid).is_err(), "{invalid:?}");
+    }
+    Ok(())
+  }
+}
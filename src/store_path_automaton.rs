@@ -1,29 +1,212 @@
-use tokio::io::AsyncRead;
+use std::{collections::{BTreeMap, HashMap}, fmt, io, pin::Pin, task::{Context, Poll, ready}};
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+use crate::store_path::StoreDir;
+
+const HASH_LEN: usize = 32;
+const BUF_SIZE: usize = 64 * 1024; // MUST be bigger than HASH_LEN
+
+/// Error returned (wrapped in an `io::Error`) by a `StorePathReader` with a plain replacement table when a store path hash is missing from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownHashError {
+  pub hash: [u8; HASH_LEN],
+  pub offset: u64, // offset of the hash in the stream
+}
+
+impl fmt::Display for UnknownHashError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "no replacement for store path hash {} at offset {}", String::from_utf8_lossy(&self.hash), self.offset)
+  }
+}
 
+impl std::error::Error for UnknownHashError {}
+
+impl UnknownHashError {
+  /// Returns the `UnknownHashError` wrapped in `error`, if any.
+  pub fn from_io(error: &io::Error) -> Option<&Self> {
+    error.get_ref().and_then(|e| e.downcast_ref())
+  }
+}
 
+/// What a `StorePathReader` does with the store path hashes it finds.
+pub trait HashRewriter {
+  /// Called on every store path hash, in stream order, with the offset of the hash in the stream.
+  /// The hash can be modified in place, and an error aborts the read.
+  fn rewrite(&mut self, offset: u64, hash: &mut [u8; HASH_LEN]) -> io::Result<()>;
+}
+
+/// Rewrites the hashes according to the table and fails with an `UnknownHashError` on the first one missing from it.
+impl HashRewriter for HashMap<[u8; HASH_LEN], [u8; HASH_LEN]> {
+  fn rewrite(&mut self, offset: u64, hash: &mut [u8; HASH_LEN]) -> io::Result<()> {
+    match self.get(hash) {
+      Some(r) => {
+        *hash = *r;
+        Ok(())
+      }
+      None => Err(io::Error::new(io::ErrorKind::InvalidData, UnknownHashError { hash: *hash, offset })),
+    }
+  }
+}
 
-struct StorePathReader<R> {
-  reader: R,
-  automaton: StorePathAutomaton,
+/// Outcome of a replacement pass that carries on past the hashes missing from the replacement table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplacementReport {
+  pub replaced: u64, // number of replaced occurrences
+  pub unknown: BTreeMap<[u8; HASH_LEN], Vec<u64>>, // offsets of the occurrences of each hash missing from the table
 }
 
-impl<R: AsyncRead> StorePathReader<R> {
-  pub fn new(reader: R) -> Self {
+impl ReplacementReport {
+  /// Whether every store path hash had a replacement, i.e. the output is fully rewritten.
+  pub fn is_complete(&self) -> bool {
+    self.unknown.is_empty()
+  }
+}
+
+/// Rewrites the hashes according to the table, leaves the others untouched and records both in a `ReplacementReport`.
+pub struct ReportingRewriter {
+  replacements: HashMap<[u8; HASH_LEN], [u8; HASH_LEN]>,
+  report: ReplacementReport,
+}
+
+impl ReportingRewriter {
+  pub fn new(replacements: HashMap<[u8; HASH_LEN], [u8; HASH_LEN]>) -> Self {
+    Self {
+      replacements,
+      report: ReplacementReport::default(),
+    }
+  }
+
+  pub fn report(&self) -> &ReplacementReport {
+    &self.report
+  }
+
+  pub fn into_report(self) -> ReplacementReport {
+    self.report
+  }
+}
+
+impl HashRewriter for ReportingRewriter {
+  fn rewrite(&mut self, offset: u64, hash: &mut [u8; HASH_LEN]) -> io::Result<()> {
+    match self.replacements.get(hash) {
+      Some(r) => {
+        *hash = *r;
+        self.report.replaced += 1;
+      }
+      None => self.report.unknown.entry(*hash).or_default().push(offset),
+    }
+    Ok(())
+  }
+}
+
+pin_project! {
+  /// Wraps an `AsyncRead` and hands every store path hash it yields to a `HashRewriter`, by default a plain replacement table.
+  ///
+  /// The last `HASH_LEN` scanned bytes are held back until we know they can't be part of a store path,
+  /// so that matches spanning two reads of the underlying reader are rewritten too.
+  pub struct StorePathReader<R, H = HashMap<[u8; HASH_LEN], [u8; HASH_LEN]>> {
+    #[pin]
+    reader: R,
+    automaton: StorePathAutomaton,
+    rewriter: H,
+    buf: Box<[u8]>,
+    offset: u64, // offset of the beginning of the buffer in the stream
+    start: usize, // start of the bytes not yet yielded
+    scanned: usize, // end of the bytes already fed to the automaton
+    end: usize, // end of the bytes read from the underlying reader
+    eof: bool,
+  }
+}
+
+impl<R: AsyncRead, H: HashRewriter> StorePathReader<R, H> {
+  pub fn new(reader: R, rewriter: H) -> Self {
+    Self::with_capacity(BUF_SIZE, reader, rewriter)
+  }
+
+  pub fn with_capacity(capacity: usize, reader: R, rewriter: H) -> Self {
+    Self::with_store_dir(&StoreDir::default(), capacity, reader, rewriter)
+  }
+
+  pub fn with_store_dir(store_dir: &StoreDir, capacity: usize, reader: R, rewriter: H) -> Self {
+    assert!(capacity > HASH_LEN, "capacity must be bigger than the length of a store path hash");
     Self {
       reader,
-      automaton: StorePathAutomaton::new(),
+      automaton: StorePathAutomaton::with_store_dir(store_dir),
+      rewriter,
+      buf: vec![0; capacity].into_boxed_slice(),
+      offset: 0,
+      start: 0,
+      scanned: 0,
+      end: 0,
+      eof: false,
     }
   }
+
+  pub fn rewriter(&self) -> &H {
+    &self.rewriter
+  }
+
+  pub fn into_inner(self) -> R {
+    self.reader
+  }
+
+  pub fn into_rewriter(self) -> H {
+    self.rewriter
+  }
 }
 
-impl<R: AsyncRead> AsyncRead for StorePathReader<R> {
-    fn poll_read(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        todo!()
+impl<R: AsyncRead, H: HashRewriter> AsyncBufRead for StorePathReader<R, H> {
+  fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+    let mut this = self.project();
+
+    loop {
+      // bytes before `ready` can't be part of a store path hash we haven't seen the end of yet
+      let ready = if *this.eof { *this.end } else { this.scanned.saturating_sub(HASH_LEN) };
+      if *this.start < ready || *this.eof {
+        return Poll::Ready(Ok(&this.buf[*this.start..ready]));
+      }
+
+      // make room for the next read by moving the bytes we still hold to the beginning of the buffer
+      if *this.end == this.buf.len() {
+        this.buf.copy_within(*this.start..*this.end, 0);
+        *this.offset += *this.start as u64;
+        *this.scanned -= *this.start;
+        *this.end -= *this.start;
+        *this.start = 0;
+      }
+
+      let mut read_buf = ReadBuf::new(&mut this.buf[*this.end..]);
+      ready!(this.reader.as_mut().poll_read(cx, &mut read_buf))?;
+      let l = read_buf.filled().len();
+      if l == 0 {
+        *this.eof = true;
+      }
+      *this.end += l;
+
+      // actual search and replace
+      let mut i = *this.scanned;
+      while let Some(m) = this.automaton.find(&this.buf[i..*this.end]) {
+        let hash_start = i + m - HASH_LEN; // the automaton stops on the "-" following the hash
+        let hash: &mut [u8; HASH_LEN] = (&mut this.buf[hash_start..i + m]).try_into().unwrap();
+        this.rewriter.rewrite(*this.offset + hash_start as u64, hash)?;
+        i += m + 1;
+      }
+      *this.scanned = *this.end;
     }
+  }
+
+  fn consume(self: Pin<&mut Self>, amt: usize) {
+    *self.project().start += amt;
+  }
+}
+
+impl<R: AsyncRead, H: HashRewriter> AsyncRead for StorePathReader<R, H> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let buf = ready!(self.as_mut().poll_fill_buf(cx))?;
+    let l = buf.len().min(out.remaining());
+    out.put_slice(&buf[..l]);
+    self.consume(l);
+    Poll::Ready(Ok(()))
+  }
 }
 
 /// Custom automaton to match store paths.
@@ -31,89 +214,104 @@ impl<R: AsyncRead> AsyncRead for StorePathReader<R> {
 /// Since the matching pattern is directly written in code instead of being interpreted from an NFA or DFA, it should be much faster.
 /// This customized algorithm should have a better time complexity than a compiled NFA and a much much much better space complexity than a compiled DFA (full/hybrid & sparce/dense),
 /// making it faster and more memory efficient than both.
-
+///
+/// The store directory prefix (e.g. "/nix/store/") is matched with a precomputed KMP transition table and the hash with a simple counter.
+/// Both run side by side: since the prefix ends with "/", which isn't part of the hash alphabet, a new prefix match always interrupts the hash being matched,
+/// which is how "/nix/store/nix/store/<hash>-" only matches once.
+#[derive(Debug, Clone)]
 pub struct StorePathAutomaton {
-  state: usize,
-  overlapping_matchs: bool, // are we matching "/nix/store/nix" until now
+  prefix: Box<[u8]>, // "<store dir>/"
+  transitions: Box<[u8]>, // KMP transitions of the prefix: `transitions[state * 256 + byte]`
+  prefix_state: usize, // number of bytes of the prefix matched
+  hash_state: usize, // 0 if we aren't matching a hash, else 1 + the number of bytes of the hash matched
 }
 
-const OVERLAPPING_SUB_MATCH: &[u8] = b"/nix/store/nix"; // serves 2 purposes: the first 11 bytes to check if the beggining matches and the last 3 bytes to check if we are matching two overlapping matches
+impl Default for StorePathAutomaton {
+  fn default() -> Self {
+    Self::new()
+  }
+}
 
 impl StorePathAutomaton {
   pub fn new() -> Self {
+    Self::with_store_dir(&StoreDir::default())
+  }
+
+  pub fn with_store_dir(store_dir: &StoreDir) -> Self {
+    let prefix = format!("{}/", store_dir).into_bytes().into_boxed_slice();
+    assert!(prefix.len() < u8::MAX as usize, "store dir too long");
+
+    // classic KMP automaton construction, `fallback` being the state we'd be in if we had started matching one byte later
+    let mut transitions = vec![0u8; (prefix.len() + 1) * 256].into_boxed_slice();
+    transitions[prefix[0] as usize] = 1;
+    let mut fallback = 0;
+    for state in 1..=prefix.len() {
+      for byte in 0..256 {
+        transitions[state * 256 + byte] = transitions[fallback * 256 + byte];
+      }
+      if state < prefix.len() {
+        transitions[state * 256 + prefix[state] as usize] = state as u8 + 1;
+        fallback = transitions[fallback * 256 + prefix[state] as usize] as usize;
+      }
+    }
+
     Self {
-      state: 0,
-      overlapping_matchs: true,
+      prefix,
+      transitions,
+      prefix_state: 0,
+      hash_state: 0,
     }
   }
 
+  /// Feeds `bytes` to the automaton until a match ends, and returns the index of the byte ending it.
+  ///
+  /// Stretches of bytes that can't start a match are skipped with `memchr`, which is much faster than feeding them one by one.
+  pub fn find(&mut self, bytes: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i < bytes.len() {
+      if self.prefix_state == 0 && self.hash_state == 0 { // only the first byte of the prefix can start a match
+        i += memchr::memchr(self.prefix[0], &bytes[i..])?;
+      }
+      if self.next(bytes[i]) {
+        return Some(i);
+      }
+      i += 1;
+    }
+    None
+  }
+
   #[inline(always)]
   pub fn next(&mut self, byte: u8) -> bool {
-    let restart = match self.state {
-      0..=10 => { // matching "/nix/store/"
-        if byte == OVERLAPPING_SUB_MATCH[self.state] { // matching "/nix/store/"
-          false
-        } else if self.state == 5 && byte == b'n' { // matching "/nix/n"
-          self.state = 1; // backtrack to "/n"
-          false
-        } else {
-          true
-        }
-      }
-      11..=42 => { // match the 32 bytes long base32 (nix specific) hash 
-        if self.overlapping_matchs && self.state < 14 && byte == OVERLAPPING_SUB_MATCH[self.state] { // matching "/nix/store/nix" which fits two overlapping matches
-          false
-        } else {
-          if self.state < 14 { // we don't match "/nix/store/nix" anymore
-            self.overlapping_matchs = false;
-          }
-          match byte {
-            b'0'..=b'9' | b'a'..=b'd' /* e */ | b'f'..=b'n' /* o */ | b'p'..=b's' /* t & u */ | b'v'..=b'z' => { // digits (10) + alphabet (26) without eout (-4) = 32
-              false
-            }
-            _ => {
-              if self.overlapping_matchs && self.state == 14 && byte == b'/' { // we matched "/nix/store/nix/" so we backtrack to "/nix/"
-                self.state = 4;
-                false
-              } else {
-                true
-              }
-            }
-          }
+    let mut matched = false;
+
+    if self.hash_state > HASH_LEN { // match final "-"
+      matched = byte == b'-';
+      self.hash_state = 0;
+    } else if self.hash_state > 0 { // match the 32 bytes long base32 (nix specific) hash
+      match byte {
+        b'0'..=b'9' | b'a'..=b'd' /* e */ | b'f'..=b'n' /* o */ | b'p'..=b's' /* t & u */ | b'v'..=b'z' => { // digits (10) + alphabet (26) without eout (-4) = 32
+          self.hash_state += 1;
         }
-      }
-      43 => { // match final "-"
-        if byte == b'-' {
-          return true;
-        } else {
-          true
+        _ => {
+          self.hash_state = 0;
         }
       }
-      _ => {
-        // unsafe { std::hint::unreachable_unchecked() }
-        unreachable!()
-      }
-    };
-
-    if restart {
-      self.overlapping_matchs = true;
+    }
 
-      if byte == b'/' {
-        self.state = 1;
-      } else {
-        self.state = 0;
-      }
-    } else {
-      self.state += 1;
+    self.prefix_state = self.transitions[self.prefix_state * 256 + byte as usize] as usize;
+    if self.prefix_state == self.prefix.len() { // matched "<store dir>/", now match the hash
+      self.hash_state = 1;
     }
 
-    false
+    matched
   }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
   use super::*;
+  use tokio::io::AsyncReadExt;
 
   fn helper(bytes: &[u8]) -> bool {
     let mut automaton = StorePathAutomaton::new();
@@ -521,4 +719,68 @@ mod tests {
     assert_eq!(helper(b"/nix/store#/nix/store#/nix/store/000@0000000000000000000000000000-"), false);
   }
 
+  fn helper_in(store_dir: &str, bytes: &[u8]) -> bool {
+    let mut automaton = StorePathAutomaton::with_store_dir(&store_dir.parse().unwrap());
+    bytes.iter().any(|b| automaton.next(*b))
+  }
+
+  #[test]
+  fn other_store_dirs() {
+    assert_eq!(helper_in("/gnu/store", b"/gnu/store/00000000000000000000000000000000-"), true);
+    assert_eq!(helper_in("/gnu/store", b"/gnu/gnu/store/00000000000000000000000000000000-"), true);
+    assert_eq!(helper_in("/gnu/store/", b"/gnu/store/00000000000000000000000000000000-"), true);
+    assert_eq!(helper_in("/gnu/store", b"/nix/store/00000000000000000000000000000000-"), false);
+
+    assert_eq!(helper_in("/a/a", b"/a/a/00000000000000000000000000000000-"), true);
+    assert_eq!(helper_in("/a/a", b"/a/a/a/00000000000000000000000000000000-"), true);
+    assert_eq!(helper_in("/a/a", b"/a/a/a/a/00000000000000000000000000000000-"), true);
+    assert_eq!(helper_in("/a/a", b"/a//a/00000000000000000000000000000000-"), false);
+
+    assert_eq!(helper_in("/0/0", b"/0/0/0/0/00000000000000000000000000000000-"), true);
+    assert_eq!(helper_in("/0/0", b"/0/0/0000000000000000000000000000000-"), false);
+  }
+
+  #[test]
+  fn match_restarts_after_dash() {
+    let mut automaton = StorePathAutomaton::new();
+    let matches = b"/nix/store/00000000000000000000000000000000--/nix/store/11111111111111111111111111111111-".iter()
+      .filter(|b| automaton.next(**b))
+      .count();
+    assert_eq!(matches, 2);
+  }
+
+  fn replacements() -> HashMap<[u8; 32], [u8; 32]> {
+    let mut repl = HashMap::new();
+    repl.insert(*b"abcdfghijklmnpqrsvwxyz0000000000", *b"00000000000000000000000000000000");
+    repl.insert(*b"11111111111111111111111111111111", *b"22222222222222222222222222222222");
+    repl
+  }
+
+  async fn read_chunked(input: &[u8], chunk: usize, capacity: usize) -> std::io::Result<Vec<u8>> {
+    let chunks = input.chunks(chunk).map(|c| Ok::<_, std::io::Error>(bytes::Bytes::copy_from_slice(c))).collect::<Vec<_>>();
+    let reader = tokio_util::io::StreamReader::new(futures::stream::iter(chunks));
+    let mut out = Vec::new();
+    StorePathReader::with_capacity(capacity, reader, replacements()).read_to_end(&mut out).await?;
+    Ok(out)
+  }
+
+  #[tokio::test]
+  async fn reader_rewrites() -> std::io::Result<()> {
+    let input = b"abc /nix/store/abcdfghijklmnpqrsvwxyz0000000000-foo/nix/store/11111111111111111111111111111111-bar abc";
+    let expected = b"abc /nix/store/00000000000000000000000000000000-foo/nix/store/22222222222222222222222222222222-bar abc";
+    for chunk in [1, 7, 43, 44, 45, 1024] {
+      for capacity in [33, 45, 64, BUF_SIZE] {
+        assert_eq!(read_chunked(input, chunk, capacity).await?, expected, "chunk: {chunk}, capacity: {capacity}");
+      }
+    }
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn reader_fails_on_unknown_hash() {
+    let input = b"abc /nix/store/33333333333333333333333333333333-foo abc";
+    let err = read_chunked(input, 5, 40).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(UnknownHashError::from_io(&err), Some(&UnknownHashError { hash: *b"33333333333333333333333333333333", offset: 15 }));
+  }
 }
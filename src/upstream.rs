@@ -0,0 +1,297 @@
+//! The binary caches narinfos and NARs are fetched from.
+
+use std::{fmt, path::{Component, Path, PathBuf}, sync::Arc, time::Duration};
+use color_eyre::eyre;
+use futures::{TryStreamExt, future::BoxFuture};
+use tokio::io::{AsyncBufRead, AsyncReadExt, BufReader};
+use tokio_util::io::StreamReader;
+use crate::{narinfo::NarInfo, signing::PublicKey};
+
+const DEFAULT_PRIORITY: u32 = 50; // like Nix
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type FileReader = Box<dyn AsyncBufRead + Send + Unpin>;
+
+/// Where the files of a binary cache are read from.
+pub trait BinaryCache: fmt::Debug + Send + Sync {
+  /// Opens the file at `path`, relative to the root of the cache, or returns `None` if there's no such file.
+  ///
+  /// The file is read as stored, so that it matches the `FileHash` of its narinfo.
+  fn file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, eyre::Result<Option<FileReader>>>;
+
+  /// Like `file`, but decodes the file if it's served compressed, like build logs can be.
+  fn decoded_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, eyre::Result<Option<FileReader>>> {
+    self.file(path)
+  }
+}
+
+/// A binary cache served over HTTP(S).
+#[derive(Debug)]
+pub struct HttpCache {
+  url: String,
+  client: reqwest::Client, // without Accept-Encoding, so that files are received as stored
+  decoding_client: reqwest::Client, // decodes what's sent with a Content-Encoding
+}
+
+impl HttpCache {
+  pub fn new(url: &str) -> eyre::Result<Self> {
+    Ok(Self {
+      url: url.trim_end_matches('/').to_owned(),
+      client: reqwest::Client::builder().no_gzip().no_brotli().build()?,
+      decoding_client: reqwest::Client::new(),
+    })
+  }
+
+  async fn get(&self, client: &reqwest::Client, path: &str) -> eyre::Result<Option<FileReader>> {
+    let r = client.get(format!("{}/{path}", self.url)).send().await?;
+    if r.status() == reqwest::StatusCode::NOT_FOUND {
+      return Ok(None);
+    }
+
+    let stream = r.error_for_status()?.bytes_stream().map_err(std::io::Error::other);
+    Ok(Some(Box::new(StreamReader::new(stream)) as FileReader))
+  }
+}
+
+impl BinaryCache for HttpCache {
+  fn file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, eyre::Result<Option<FileReader>>> {
+    Box::pin(self.get(&self.client, path))
+  }
+
+  fn decoded_file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, eyre::Result<Option<FileReader>>> {
+    Box::pin(self.get(&self.decoding_client, path))
+  }
+}
+
+/// A binary cache in a local directory, as created by `nix copy --to file://<dir>`.
+#[derive(Debug)]
+pub struct LocalCache {
+  dir: PathBuf,
+}
+
+impl LocalCache {
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    Self { dir: dir.into() }
+  }
+}
+
+impl BinaryCache for LocalCache {
+  fn file<'a>(&'a self, path: &'a str) -> BoxFuture<'a, eyre::Result<Option<FileReader>>> {
+    Box::pin(async move {
+      // paths come from clients and narinfos, they mustn't escape the cache
+      if !Path::new(path).components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(eyre::eyre!("invalid path in binary cache: {path:?}"));
+      }
+
+      match tokio::fs::File::open(self.dir.join(path)).await {
+        Ok(file) => Ok(Some(Box::new(BufReader::new(file)) as FileReader)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+      }
+    })
+  }
+}
+
+/// A binary cache, configured as "<url>?priority=<n>&trusted-public-keys=<key>,<key>&timeout=<seconds>&trusted=1".
+///
+/// The url is either "http(s)://..." or "file://<dir>". Lower priorities are tried first.
+/// The timeout applies until the response headers are received.
+/// The narinfos of trusted upstreams aren't required to be signed, like those `nix copy --to file://<dir>` writes.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+  pub url: String,
+  pub priority: u32,
+  pub trusted_keys: Vec<PublicKey>,
+  pub trusted: bool, // narinfo signatures aren't checked
+  pub timeout: Duration,
+  cache: Arc<dyn BinaryCache>,
+}
+
+impl Upstream {
+  /// Parses an upstream, which trusts `default_keys` unless it has its own `trusted-public-keys`.
+  pub fn parse(s: &str, default_keys: &[PublicKey]) -> eyre::Result<Self> {
+    let (url, query) = s.split_once('?').unwrap_or((s, ""));
+    let url = url.trim_end_matches('/');
+    let cache: Arc<dyn BinaryCache> = if let Some(dir) = url.strip_prefix("file://") {
+      Arc::new(LocalCache::new(dir))
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+      Arc::new(HttpCache::new(url)?)
+    } else {
+      return Err(eyre::eyre!("unsupported upstream {url:?}, expected http(s):// or file://"));
+    };
+    let mut upstream = Self {
+      url: url.to_owned(),
+      priority: DEFAULT_PRIORITY,
+      trusted_keys: default_keys.to_vec(),
+      trusted: false,
+      timeout: DEFAULT_TIMEOUT,
+      cache,
+    };
+
+    // not URL decoded, base64 keys are more readable that way
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+      let (key, value) = param.split_once('=').ok_or_else(|| eyre::eyre!("invalid upstream parameter {param:?} in {s:?}"))?;
+      match key {
+        "priority" => upstream.priority = value.parse()?,
+        "trusted-public-keys" => upstream.trusted_keys = value.split(',').map(str::parse).collect::<eyre::Result<_>>()?,
+        "timeout" => upstream.timeout = Duration::from_secs(value.parse()?),
+        "trusted" => upstream.trusted = match value {
+          "1" | "true" => true,
+          "0" | "false" => false,
+          _ => return Err(eyre::eyre!("invalid value {value:?} of upstream parameter trusted in {s:?}")),
+        },
+        _ => return Err(eyre::eyre!("unknown upstream parameter {key:?} in {s:?}")),
+      }
+    }
+
+    Ok(upstream)
+  }
+
+  /// Fetches and checks the narinfo of the store path with the given hash, returns it along with its text.
+  pub async fn narinfo(&self, hash: &str) -> eyre::Result<Option<(NarInfo, String)>> {
+    let text = tokio::time::timeout(self.timeout, async {
+      let Some(mut r) = self.cache.file(&format!("{hash}.narinfo")).await? else {
+        return Ok(None);
+      };
+      let mut text = String::new();
+      r.read_to_string(&mut text).await?;
+      eyre::Ok(Some(text))
+    }).await.map_err(|_| eyre::eyre!("timeout fetching {hash}.narinfo from {}", self.url))??;
+    let Some(text) = text else {
+      return Ok(None);
+    };
+
+    let narinfo = self.verify(&text)?;
+    Ok(Some((narinfo, text)))
+  }
+
+  /// Parses a narinfo fetched from this upstream and checks its signatures, unless the upstream is trusted.
+  pub fn verify(&self, text: &str) -> eyre::Result<NarInfo> {
+    let narinfo: NarInfo = text.parse()?;
+    if !self.trusted && !narinfo.verify(&self.trusted_keys) {
+      tracing::error!(store_path = %narinfo.store_path, upstream = self.url, "narinfo isn't signed by a trusted key");
+      return Err(eyre::eyre!("narinfo of {} from {} isn't signed by a trusted key", narinfo.store_path, self.url));
+    }
+    Ok(narinfo)
+  }
+
+  /// Fetches a file, e.g. a NAR given the `URL` of its narinfo.
+  pub async fn file(&self, path: &str) -> eyre::Result<Option<FileReader>> {
+    tokio::time::timeout(self.timeout, self.cache.file(path)).await
+      .map_err(|_| eyre::eyre!("timeout fetching {path} from {}", self.url))?
+  }
+
+  /// Fetches a file which may be served compressed, see `BinaryCache::decoded_file`.
+  pub async fn decoded_file(&self, path: &str) -> eyre::Result<Option<FileReader>> {
+    tokio::time::timeout(self.timeout, self.cache.decoded_file(path)).await
+      .map_err(|_| eyre::eyre!("timeout fetching {path} from {}", self.url))?
+  }
+}
+
+/// Parses a space separated list of upstreams, sorted by priority.
+pub fn parse_upstreams(s: &str, default_keys: &[PublicKey]) -> eyre::Result<Vec<Upstream>> {
+  let mut upstreams = s.split_whitespace().map(|u| Upstream::parse(u, default_keys)).collect::<eyre::Result<Vec<_>>>()?;
+  if upstreams.is_empty() {
+    return Err(eyre::eyre!("no upstream configured"));
+  }
+  upstreams.sort_by_key(|u| u.priority); // stable, so that the order of the list breaks ties
+  Ok(upstreams)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::signing::SecretKey;
+
+  #[test]
+  fn parse() -> eyre::Result<()> {
+    let nixos: PublicKey = "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".parse()?;
+    let upstreams = parse_upstreams(
+      "https://cache.nixos.org/ https://cache.example.com?priority=10&trusted-public-keys=a-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=,b-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=&timeout=5",
+      std::slice::from_ref(&nixos),
+    )?;
+
+    assert_eq!(upstreams[0].url, "https://cache.example.com");
+    assert_eq!(upstreams[0].priority, 10);
+    assert_eq!(upstreams[0].timeout, Duration::from_secs(5));
+    assert_eq!(upstreams[0].trusted_keys.iter().map(PublicKey::name).collect::<Vec<_>>(), ["a-1", "b-1"]);
+
+    assert_eq!(upstreams[1].url, "https://cache.nixos.org");
+    assert_eq!(upstreams[1].priority, DEFAULT_PRIORITY);
+    assert_eq!(upstreams[1].trusted_keys, [nixos]);
+    assert!(!upstreams[1].trusted);
+
+    assert!(parse_upstreams("file:///srv/cache?trusted=1", &[])?[0].trusted);
+
+    assert!(parse_upstreams("", &[]).is_err());
+    assert!(parse_upstreams("https://cache.nixos.org?prio=1", &[]).is_err());
+    assert!(parse_upstreams("https://cache.nixos.org?timeout", &[]).is_err());
+    assert!(parse_upstreams("https://cache.nixos.org?trusted=yes", &[]).is_err());
+    assert!(parse_upstreams("ftp://cache.nixos.org", &[]).is_err());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn local() -> eyre::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let key: SecretKey = "test-1:AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8DoQe/884Qvh1w3RjnS8CZZ+TWMJulDV8d3IZkElUxuA==".parse()?;
+    let mut narinfo: NarInfo = "StorePath: /nix/store/3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp-hello-2.12.1
+URL: nar/hello.nar
+Compression: none
+NarHash: sha256:0hb5svmr2jxyqsnrc5n7sqgr3pp4p8cvzzc23j2pn0ar0h4zvx0s
+NarSize: 5
+".parse()?;
+    narinfo.sign(&key);
+    tokio::fs::write(dir.path().join("3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp.narinfo"), narinfo.to_string()).await?;
+    tokio::fs::create_dir(dir.path().join("nar")).await?;
+    tokio::fs::write(dir.path().join("nar/hello.nar"), "hello").await?;
+
+    let url = format!("file://{}", dir.path().display());
+    let upstream = Upstream::parse(&url, &[key.public_key()])?;
+    let (fetched, _) = upstream.narinfo("3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp").await?.unwrap();
+    assert_eq!(fetched, narinfo);
+    let mut nar = String::new();
+    upstream.file(&fetched.url).await?.unwrap().read_to_string(&mut nar).await?;
+    assert_eq!(nar, "hello");
+
+    assert!(upstream.narinfo("qn3ggz5sf3hkjs2c797xf7nan3amdxmp").await?.is_none());
+    assert!(upstream.file("nar/../../etc/passwd").await.is_err());
+    assert!(upstream.file("/etc/passwd").await.is_err());
+
+    // not signed by a trusted key
+    let upstream = Upstream::parse(&url, &[])?;
+    assert!(upstream.narinfo("3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp").await.is_err());
+
+    // unless the upstream is trusted, or not signed at all
+    let upstream = Upstream::parse(&format!("{url}?trusted=1"), &[])?;
+    assert!(upstream.narinfo("3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp").await?.is_some());
+    narinfo.sigs.clear();
+    tokio::fs::write(dir.path().join("3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp.narinfo"), narinfo.to_string()).await?;
+    assert_eq!(upstream.narinfo("3sg0djrhdnx6b5dxh8n3ax4w3l6lvhlp").await?.unwrap().0, narinfo);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn content_encoding() -> eyre::Result<()> {
+    use axum::{Router, http::header, routing::get};
+    use crate::compression::Compression;
+
+    // served like Nix uploads compressed build logs
+    let mut gzipped = Vec::new();
+    Compression::Gzip.encoder(&b"hello"[..]).read_to_end(&mut gzipped).await?;
+    let body = gzipped.clone();
+    let app = Router::new().route("/*path", get(|| async move { ([(header::CONTENT_ENCODING, "gzip")], body) }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let upstream = Upstream::parse(&url, &[])?;
+    let mut raw = Vec::new();
+    upstream.file("nar/hello.nar.gz").await?.unwrap().read_to_end(&mut raw).await?;
+    assert_eq!(raw, gzipped);
+    let mut decoded = String::new();
+    upstream.decoded_file("log/hello.drv").await?.unwrap().read_to_string(&mut decoded).await?;
+    assert_eq!(decoded, "hello");
+    Ok(())
+  }
+}